@@ -0,0 +1,41 @@
+//! Times a loop that spends its time on conditions, arithmetic and function calls.
+//! Run with `cargo bench`.
+
+use std::time::Instant;
+use cereal::value::Value;
+use cereal::vm::VM;
+
+const ITERATIONS: i64 = 500_000;
+
+const SCRIPT: &str = r#"
+FN double x DO
+    MUL result $x 2
+    RETURN $result
+ENDFN
+MOV n 0
+MOV total 0
+WHILE $n LT 500000
+    ADD n 1
+    IF $n GT 250000 AND $n NOT 0
+        CALL double $n -> twice
+        ADD total $twice
+    ENDIF
+ENDWHILE
+"#;
+
+fn main() {
+    let mut vm = VM::quiet();
+    vm.load_string(SCRIPT).expect("benchmark script should load");
+
+    let start = Instant::now();
+    vm.execute().expect("benchmark script should run");
+    let elapsed = start.elapsed();
+
+    assert_eq!(vm.get_variable("total"), Some(&Value::from(187_500_250_000)));
+    println!(
+        "loop: {} iterations in {:.3}s ({:.0} ns per iteration)",
+        ITERATIONS,
+        elapsed.as_secs_f64(),
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
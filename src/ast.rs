@@ -0,0 +1,168 @@
+use crate::command::{Command, ExecutionContext};
+use crate::expression::Expression;
+use crate::source::SourceLocation;
+use crate::error::CerealError;
+use crate::value::{self, Value};
+
+/// A whole script, parsed by `Parser::parse_program`
+#[derive(Clone, Default)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+}
+
+/// A statement along with where it was written, so errors can point at it
+pub struct Statement {
+    pub kind: StatementKind,
+    pub location: SourceLocation,
+}
+
+/// What a statement does. Blocks hold the statements between their opening and closing lines.
+pub enum StatementKind {
+    // A single command, e.g. MOV or CALL
+    Command(Box<dyn Command>),
+    // IF and each ELSEIF with the statements they guard, followed by the ELSE branch
+    If {
+        branches: Vec<(Expression, Vec<Statement>)>,
+        otherwise: Option<Vec<Statement>>,
+    },
+    While {
+        condition: Expression,
+        body: Vec<Statement>,
+    },
+    For {
+        var: String,        // Variable that receives each item
+        source: String,     // Value to split, expanded when the loop starts
+        mode: ForMode,
+        body: Vec<Statement>,
+    },
+    Try {
+        body: Vec<Statement>,
+        catch: Option<Catch>,
+        finally: Option<Vec<Statement>>,
+    },
+    Break,
+    Continue,
+    // FN <name> [params...] DO ... ENDFN
+    Function {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Statement>,
+    },
+    // IMPORT "<path>" [AS <namespace>], with the imported file already parsed
+    Import {
+        path: String,       // Path as written in the script
+        namespace: Option<String>,
+        program: Program,
+    },
+}
+
+/// The CATCH part of a TRY block
+#[derive(Clone)]
+pub struct Catch {
+    pub var: String,    // Variable that receives the error message
+    pub body: Vec<Statement>,
+}
+
+/// How a FOR loop splits its value into items
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForMode {
+    Items,      // The items of a list, or the keys of a map
+    Lines,
+    Words,
+    Split(String),
+}
+
+impl ForMode {
+    /// The words written after the value of a FOR loop to choose the mode, if any
+    pub fn to_source(&self) -> Option<String> {
+        match self {
+            ForMode::Items => None,
+            ForMode::Lines => Some("LINES".to_string()),
+            ForMode::Words => Some("WORDS".to_string()),
+            ForMode::Split(delimiter) => Some(format!("SPLIT {}", value::quote(delimiter))),
+        }
+    }
+
+    /// Expands the source of a FOR loop and splits it into the items to loop over
+    pub fn items(&self, source: &str, context: &ExecutionContext) -> Result<Vec<Value>, CerealError> {
+        let text = || context.expand_variables(source);
+
+        match self {
+            ForMode::Items => match context.evaluate(source)? {
+                Value::List(items) => Ok(items),
+                Value::Map(entries) => Ok(entries.into_keys().map(Value::String).collect()),
+                _ => Err(CerealError::runtime(format!(
+                    "FOR: '{}' is not a list or map, use LINES, WORDS or SPLIT to loop over text",
+                    source
+                ))),
+            },
+            ForMode::Lines => Ok(text()?.lines().map(Value::from).collect()),
+            ForMode::Words => Ok(text()?.split_whitespace().map(Value::from).collect()),
+            ForMode::Split(delimiter) => {
+                let text = text()?;
+                if text.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let delimiter = context.expand_variables(delimiter)?;
+                Ok(text.split(delimiter.as_str()).map(Value::from).collect())
+            }
+        }
+    }
+}
+
+impl StatementKind {
+    /// The keyword or command the statement was written with
+    pub fn name(&self) -> &str {
+        match self {
+            StatementKind::Command(command) => command.name(),
+            StatementKind::If { .. } => "IF",
+            StatementKind::While { .. } => "WHILE",
+            StatementKind::For { .. } => "FOR",
+            StatementKind::Try { .. } => "TRY",
+            StatementKind::Break => "BREAK",
+            StatementKind::Continue => "CONTINUE",
+            StatementKind::Function { .. } => "FN",
+            StatementKind::Import { .. } => "IMPORT",
+        }
+    }
+}
+
+impl Clone for Statement {
+    fn clone(&self) -> Self {
+        let kind = match &self.kind {
+            StatementKind::Command(command) => StatementKind::Command(command.box_clone()),
+            StatementKind::If { branches, otherwise } => StatementKind::If {
+                branches: branches.clone(),
+                otherwise: otherwise.clone(),
+            },
+            StatementKind::While { condition, body } => StatementKind::While {
+                condition: condition.clone(),
+                body: body.clone(),
+            },
+            StatementKind::For { var, source, mode, body } => StatementKind::For {
+                var: var.clone(),
+                source: source.clone(),
+                mode: mode.clone(),
+                body: body.clone(),
+            },
+            StatementKind::Try { body, catch, finally } => StatementKind::Try {
+                body: body.clone(),
+                catch: catch.clone(),
+                finally: finally.clone(),
+            },
+            StatementKind::Break => StatementKind::Break,
+            StatementKind::Continue => StatementKind::Continue,
+            StatementKind::Function { name, params, body } => StatementKind::Function {
+                name: name.clone(),
+                params: params.clone(),
+                body: body.clone(),
+            },
+            StatementKind::Import { path, namespace, program } => StatementKind::Import {
+                path: path.clone(),
+                namespace: namespace.clone(),
+                program: program.clone(),
+            },
+        };
+        Statement { kind, location: self.location.clone() }
+    }
+}
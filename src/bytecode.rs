@@ -0,0 +1,184 @@
+use std::fmt;
+use crate::ast::ForMode;
+use crate::commands::{Operator, DEFAULT_ABORT_CODE};
+use crate::expression::Comparison;
+use crate::source::SourceLocation;
+use crate::value::{self, Operand};
+
+/// A single bytecode instruction. Instructions are plain data, so compiled programs can be
+/// stored and loaded again. Jump targets are positions in the chunk the instruction belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    // Push the value of an operand onto the stack
+    Load(Operand),
+    // Pop a value into a variable
+    Store(String),
+    // Set a variable or register straight from an operand, e.g. MOV r0 $website
+    Move(String, Operand),
+    // Discard the value on top of the stack
+    Pop,
+    // Pop two values and push whether the comparison between them holds
+    Compare(Comparison),
+    // Pop a condition and push the opposite
+    Not,
+    Jump(usize),
+    // Pop a condition and jump if it doesn't hold
+    JumpIfFalse(usize),
+    // Push the items a FOR loop visits
+    Items(ForMode, String),
+    // Set the variable to the next item of the loop on top of the stack, or jump once there are none left
+    Next(String, usize),
+    // Handle errors raised before the matching EndTry by jumping to the given position
+    Try(usize),
+    EndTry,
+    // Take the error being handled into the variable, or jump to the given position for an ABORT
+    Catch(String, usize),
+    // Raise the error being handled again, once FINALLY has run
+    Rethrow,
+    // Pop the given number of arguments, call the function and push the value it returns
+    Call(String, usize),
+    // Pop the value to return and end the function (or the script at the top level)
+    Ret,
+    LibCall(String),
+    // Queue a chunk to run when the current function call or script ends
+    Defer(Chunk),
+    Global(Vec<String>),
+    // Pop two numbers and push the result of the operator, e.g. a - b for SUB
+    Arithmetic(Operator),
+    // Pop a number and combine the variable with it, e.g. ADD n 1
+    Update(Operator, String),
+    // Pop text and print it
+    Print,
+    // Pop a command and run it in the shell
+    Exec,
+    Input(String),
+    // Pop a value into an environment variable
+    SetEnv(String),
+    RemoveEnv(String),
+    // Pop a message and stop the script with the given exit code
+    Abort(i32),
+}
+
+/// Compiled instructions along with where each of them came from, for reporting errors
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub ops: Vec<Op>,
+    // Source locations used by the instructions, each stored once
+    pub locations: Vec<SourceLocation>,
+    // Index into `locations` for each instruction
+    pub op_locations: Vec<usize>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an instruction written at the given location, returning its position
+    pub fn push(&mut self, op: Op, location: &SourceLocation) -> usize {
+        if self.locations.last() != Some(location) {
+            self.locations.push(location.clone());
+        }
+        self.ops.push(op);
+        self.op_locations.push(self.locations.len() - 1);
+        self.ops.len() - 1
+    }
+
+    /// Where the instruction at the given position came from
+    pub fn location(&self, position: usize) -> Option<&SourceLocation> {
+        self.op_locations.get(position).and_then(|index| self.locations.get(*index))
+    }
+
+    /// Points a jump placed earlier at the given position
+    pub fn patch(&mut self, position: usize, target: usize) {
+        match &mut self.ops[position] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::Next(_, to) | Op::Try(to) | Op::Catch(_, to) => *to = target,
+            _ => {}
+        }
+    }
+
+    /// Adds the instructions of another chunk to the end of this one, moving its jumps along with them
+    pub fn append(&mut self, other: Chunk) {
+        let offset = self.ops.len();
+        let location_offset = self.locations.len();
+        for (position, op) in other.ops.into_iter().enumerate() {
+            self.ops.push(op);
+            if let Some(target) = jump_target(&self.ops[offset + position]) {
+                self.patch(offset + position, target + offset);
+            }
+        }
+        self.locations.extend(other.locations);
+        self.op_locations.extend(other.op_locations.into_iter().map(|index| index + location_offset));
+    }
+
+    /// Lists the instructions one per line with their position and source line number,
+    /// showing `|` while the line stays the same. Deferred chunks are listed under their DEFER.
+    pub fn disassemble(&self) -> String {
+        let mut listing = String::new();
+        let mut previous = None;
+
+        for (position, op) in self.ops.iter().enumerate() {
+            let location = self.location(position);
+            let line = match location.map(|location| location.line) {
+                Some(line) if previous != Some(line) => line.to_string(),
+                _ => "|".to_string(),
+            };
+            previous = location.map(|location| location.line);
+            listing.push_str(&format!("{:04} {:>4}  {}\n", position, line, op));
+
+            if let Op::Defer(chunk) = op {
+                for nested in chunk.disassemble().lines() {
+                    listing.push_str(&format!("{:>11}{}\n", "", nested));
+                }
+            }
+        }
+        listing
+    }
+}
+
+/// Shows an instruction the way the disassembler lists it, e.g. `MOV r0 $website` or `JUMP 0004`
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Load(operand) => write!(f, "LOAD {}", operand.to_source()),
+            Op::Store(name) => write!(f, "STORE {}", name),
+            Op::Move(name, operand) => write!(f, "MOV {} {}", name, operand.to_source()),
+            Op::Pop => write!(f, "POP"),
+            Op::Compare(comparison) => write!(f, "{}", comparison.name()),
+            Op::Not => write!(f, "NOT"),
+            Op::Jump(target) => write!(f, "JUMP {:04}", target),
+            Op::JumpIfFalse(target) => write!(f, "JUMP_IF_FALSE {:04}", target),
+            Op::Items(mode, source) => match mode.to_source() {
+                Some(mode) => write!(f, "ITEMS {} {}", value::quote(source), mode),
+                None => write!(f, "ITEMS {}", value::quote(source)),
+            },
+            Op::Next(var, target) => write!(f, "NEXT {} {:04}", var, target),
+            Op::Try(target) => write!(f, "TRY {:04}", target),
+            Op::EndTry => write!(f, "END_TRY"),
+            Op::Catch(var, target) => write!(f, "CATCH {} {:04}", var, target),
+            Op::Rethrow => write!(f, "RETHROW"),
+            Op::Call(name, count) => write!(f, "CALL {} {}", name, count),
+            Op::Ret => write!(f, "RET"),
+            Op::LibCall(name) => write!(f, "LIBCALL {}", name),
+            Op::Defer(_) => write!(f, "DEFER"),
+            Op::Global(names) => write!(f, "GLOBAL {}", names.join(" ")),
+            Op::Arithmetic(operator) => write!(f, "{}", operator.name()),
+            Op::Update(operator, target) => write!(f, "{} {}", operator.name(), target),
+            Op::Print => write!(f, "PRINT"),
+            Op::Exec => write!(f, "EXEC"),
+            Op::Input(var) => write!(f, "INPUT {}", var),
+            Op::SetEnv(name) => write!(f, "ENV {}", name),
+            Op::RemoveEnv(name) => write!(f, "UNSET_ENV {}", name),
+            Op::Abort(DEFAULT_ABORT_CODE) => write!(f, "ABORT"),
+            Op::Abort(code) => write!(f, "ABORT {}", code),
+        }
+    }
+}
+
+/// Where an instruction jumps to, if it can jump
+pub fn jump_target(op: &Op) -> Option<usize> {
+    match op {
+        Op::Jump(to) | Op::JumpIfFalse(to) | Op::Next(_, to) | Op::Try(to) | Op::Catch(_, to) => Some(*to),
+        _ => None,
+    }
+}
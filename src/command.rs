@@ -1,35 +1,52 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::any::Any;
 use crate::vm::VM;
+use crate::bytecode::{Chunk, Op};
+use crate::error::CerealError;
+use crate::value::Value;
+use crate::filters::{self, FilterRegistry};
+use crate::consts::ScriptVariables;
 
 // Base trait for all commands in the scripting language
 pub trait Command: Any {
-    // Execute the command with given context
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String>;
-    
     // Get the name of the command
     fn name(&self) -> &str;
-    
-    // Whether this command affects control flow
-    fn is_control_flow(&self) -> bool {
-        false
-    }
+
+    // Add the bytecode instructions that carry out the command
+    fn compile(&self, code: &mut Vec<Op>);
+
+    // Format the command the way it would be written in a script
+    fn to_source(&self) -> String;
     
     // Create a clone of this command
     fn box_clone(&self) -> Box<dyn Command>;
 }
 
+// Variables local to a single function call
+#[derive(Debug, Default)]
+pub struct Scope {
+    // Variables defined inside the function
+    pub variables: HashMap<String, Value>,
+    // Names declared with GLOBAL, which refer to the global variables instead
+    pub globals: HashSet<String>,
+    // Namespace of the function being called, if it was imported with IMPORT ... AS
+    pub namespace: Option<String>,
+}
+
 // Execution context that holds the current state during command execution
 pub struct ExecutionContext<'a> {
-    // Variables defined in the current scope
-    pub variables: HashMap<String, String>,
+    // Global variables, visible from the top level and every function
+    pub variables: HashMap<String, Value>,
+    // Scopes of the functions currently being called, innermost last
+    pub scopes: Vec<Scope>,
     // Current command arguments
     pub args: Vec<String>,
-    // Command to skip until (for conditional execution)
-    pub skip_until: Option<String>,  // Make this public
-    // Value to be returned from current execution
-    #[allow(dead_code)]
-    return_value: Option<String>,
+    // Whether undefined variables expand to an empty string instead of being an error
+    pub allow_undefined: bool,
+    // Filters that can be applied when interpolating, e.g. ${name | upper}
+    pub filters: FilterRegistry,
+    // Compiled statements queued by DEFER for the script and each function call, innermost last
+    deferred: Vec<Vec<Chunk>>,
     // Reference to the VM for advanced operations
     #[allow(dead_code)]
     vm: Option<&'a mut VM>,
@@ -40,10 +57,12 @@ impl<'a> ExecutionContext<'a> {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
+            scopes: Vec::new(),
             args: Vec::new(),
-            return_value: None,
+            allow_undefined: false,
+            filters: FilterRegistry::new(),
+            deferred: Vec::new(),
             vm: None,
-            skip_until: None,
         }
     }
 
@@ -51,17 +70,91 @@ impl<'a> ExecutionContext<'a> {
     pub fn with_vm(vm: &'a mut VM) -> Self {
         Self {
             variables: HashMap::new(),
+            scopes: Vec::new(),
             args: Vec::new(),
-            return_value: None,
+            allow_undefined: false,
+            filters: FilterRegistry::new(),
+            deferred: Vec::new(),
             vm: Some(vm),
-            skip_until: None,
         }
     }
 
     // Set a variable in the current scope
-    #[allow(dead_code)]
-    pub fn set_variable(&mut self, name: String, value: String) {
-        self.variables.insert(name, value);
+    pub fn set_variable(&mut self, name: String, value: impl Into<Value>) {
+        let value = value.into();
+        match self.scopes.last_mut() {
+            Some(scope) if !scope.globals.contains(&name) => {
+                scope.variables.insert(name, value);
+            }
+            _ => {
+                self.variables.insert(name, value);
+            }
+        }
+    }
+
+    // Like set_variable, but only copies the name when the variable is new
+    pub fn assign(&mut self, name: &str, value: Value) {
+        let variables = match self.scopes.last_mut() {
+            Some(scope) if !scope.globals.contains(name) => &mut scope.variables,
+            _ => &mut self.variables,
+        };
+        match variables.get_mut(name) {
+            Some(slot) => *slot = value,
+            None => {
+                variables.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    // Look up a variable in the current scope, falling back to the globals
+    pub fn get_variable(&self, name: &str) -> Option<&Value> {
+        match self.scopes.last() {
+            Some(scope) if !scope.globals.contains(name) => scope
+                .variables
+                .get(name)
+                .or_else(|| self.variables.get(name)),
+            _ => self.variables.get(name),
+        }
+    }
+
+    // Set or remove an environment variable of the VM process, keeping the global $env map in step
+    pub fn set_env(&mut self, name: &str, value: Option<String>) {
+        let entries = match self.variables.get_mut(ScriptVariables::ENV) {
+            Some(Value::Map(entries)) => Some(entries),
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                std::env::set_var(name, &value);
+                if let Some(entries) = entries {
+                    entries.insert(name.to_string(), Value::String(value));
+                }
+            }
+            None => {
+                std::env::remove_var(name);
+                if let Some(entries) = entries {
+                    entries.remove(name);
+                }
+            }
+        }
+    }
+
+    // Make later writes to the variable in the current scope go to the globals
+    pub fn declare_global(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.variables.remove(name);
+            scope.globals.insert(name.to_string());
+        }
+    }
+
+    // Start a new scope for a function call
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Scope::default());
+    }
+
+    // Discard the scope of the function call that just finished
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
     }
 
     // Get the current command arguments
@@ -71,52 +164,192 @@ impl<'a> ExecutionContext<'a> {
     }
 
     // Set the current command arguments
+    #[allow(dead_code)]
     pub fn set_args(&mut self, args: Vec<String>) {
         self.args = args;
     }
 
-    // Set a value to be returned
-    #[allow(dead_code)]
-    pub fn set_return_value(&mut self, value: String) {
-        self.return_value = Some(value);
+    // Start collecting deferred commands for a script or function call
+    pub fn push_defer_frame(&mut self) {
+        self.deferred.push(Vec::new());
     }
 
-    // Take and clear the return value
-    #[allow(dead_code)]
-    pub fn take_return_value(&mut self) -> Option<String> {
-        self.return_value.take()
+    // Queue a compiled statement to run when the current script or function call ends
+    pub fn defer(&mut self, chunk: Chunk) -> Result<(), CerealError> {
+        match self.deferred.last_mut() {
+            Some(frame) => {
+                frame.push(chunk);
+                Ok(())
+            }
+            None => Err(CerealError::runtime("DEFER can only be used inside a script or function")),
+        }
+    }
+
+    // Take the statements deferred by the current script or function call, in the order they were queued
+    pub fn pop_defer_frame(&mut self) -> Vec<Chunk> {
+        self.deferred.pop().unwrap_or_default()
+    }
+
+    // Expand variables in a string (e.g., $var or ${var} becomes the value of var) in a single pass,
+    // so expanded values are never expanded again. Lists and maps can be indexed with $hosts[0]
+    // or $cfg.port, and $$ is a literal $. Variables that aren't defined are an error, unless
+    // allow_undefined is set, in which case they expand to an empty string.
+    pub fn expand_variables(&self, input: &str) -> Result<String, CerealError> {
+        let mut result = String::new();
+        let mut rest = input;
+
+        while let Some(position) = rest.find('$') {
+            result.push_str(&rest[..position]);
+            let reference = &rest[position + 1..];
+            let (value, length) = self.expand_reference(reference)?;
+            result.push_str(&value.to_string());
+            rest = &reference[length..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    // Get the value of text such as a command argument. A lone reference like $hosts keeps
+    // the type of its value, anything else is expanded into a string.
+    pub fn evaluate(&self, text: &str) -> Result<Value, CerealError> {
+        if let Some(reference) = text.strip_prefix('$') {
+            let (value, length) = self.expand_reference(reference)?;
+            if length == reference.len() {
+                return Ok(value);
+            }
+        }
+        Ok(Value::String(self.expand_variables(text)?))
     }
 
-    // Expand variables in a string (e.g., $var becomes the value of var)
-    pub fn expand_variables(&self, input: &str) -> String {
-        let mut result = input.to_string();
+    // Expand what follows a $, returning its value and the length of the text used.
+    // A $ that doesn't start a reference (e.g. in "$5") is kept as it is.
+    fn expand_reference(&self, text: &str) -> Result<(Value, usize), CerealError> {
+        if text.starts_with('$') {
+            return Ok((Value::from("$"), 1));
+        }
 
-        for (key, value) in &self.variables {
-            result = result.replace(&format!("${}", key), value);
+        if let Some(inner) = text.strip_prefix('{') {
+            let end = matching_brace(inner)
+                .ok_or_else(|| CerealError::runtime(format!("Missing '}}' after '${}'", text)))?;
+            return Ok((self.expand_braced(&inner[..end])?, end + 2));
+        }
+
+        if !text.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return Ok((Value::from("$"), 0));
+        }
+        match self.read_reference(text)? {
+            Some((value, length)) => Ok((value.clone(), length)),
+            None => {
+                let name_length = identifier_length(text);
+                let value = self.undefined(&format!("Variable '{}' is not defined", &text[..name_length]))?;
+                Ok((value, name_length))
+            }
         }
-        result
     }
 
-    // Set which command to skip until
-    pub fn set_skip_until(&mut self, command: &str) {
-        self.skip_until = Some(command.to_string());
+    // Expand the inside of ${...}, passing the value through any filters, e.g. ${name | trim | upper}
+    fn expand_braced(&self, content: &str) -> Result<Value, CerealError> {
+        let segments = filters::split_pipeline(content);
+        if segments.len() == 1 {
+            return self.expand_modifiers(content);
+        }
+
+        let mut value = self.expand_modifiers(segments[0].trim())?;
+        for segment in &segments[1..] {
+            let (name, args) = filters::parse_call(segment)?;
+            let args = args
+                .iter()
+                .map(|arg| self.expand_variables(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            value = self.filters.apply(name, &value, &args)?;
+        }
+        Ok(value)
     }
 
-    // Clear the skip flag
-    pub fn clear_skip(&mut self) {
-        self.skip_until = None;
+    // Expand a reference inside ${...}, optionally followed by
+    // :-default (used when the value is unset or empty), :?message (an error when unset or empty)
+    // or :+alternative (used only when the value is set and not empty)
+    fn expand_modifiers(&self, content: &str) -> Result<Value, CerealError> {
+        let modifier = content
+            .match_indices(':')
+            .find(|(position, _)| content[position + 1..].starts_with(['-', '?', '+']));
+
+        let Some((position, _)) = modifier else {
+            return match self.lookup(content)? {
+                Some(value) => Ok(value.clone()),
+                None => self.undefined(&format!("Variable '{}' is not defined", content)),
+            };
+        };
+
+        let reference = &content[..position];
+        let word = &content[position + 2..];
+        let value = self.lookup(reference)?.filter(|value| !value.to_string().is_empty());
+
+        match (&content[position + 1..position + 2], value) {
+            ("-", Some(value)) | ("?", Some(value)) => Ok(value.clone()),
+            ("-", None) => self.evaluate(word),
+            ("?", None) if word.is_empty() => Err(CerealError::runtime(format!("Variable '{}' is not set", reference))),
+            ("?", None) => Err(CerealError::runtime(self.expand_variables(word)?)),
+            (_, Some(_)) => self.evaluate(word),
+            (_, None) => Ok(Value::from("")),
+        }
     }
 
-    // Check if we should skip the current command
-    pub fn should_skip(&self, command_name: &str) -> bool {
-        if let Some(skip_until) = &self.skip_until {
-            if command_name == skip_until {
-                return false;  // Don't skip the ending command
-            }
-            true
+    // Look up a complete reference such as cfg.hosts[0], returning None if any part of it can't be found
+    fn lookup(&self, reference: &str) -> Result<Option<&Value>, CerealError> {
+        Ok(self.read_reference(reference)?.filter(|(_, length)| *length == reference.len()).map(|(value, _)| value))
+    }
+
+    // The value of a reference that can't be found: an error, or null if undefined variables are allowed
+    fn undefined(&self, message: &str) -> Result<Value, CerealError> {
+        if self.allow_undefined {
+            Ok(Value::Null)
         } else {
-            false
+            Err(CerealError::runtime(message))
+        }
+    }
+
+    // Read a variable name and any .field or [index] accessors from the start of the text,
+    // returning the value and the length of the text used, or None if the variable isn't defined.
+    // Accessors that don't apply to the value (e.g. the "." in "$file.txt" when file is a string)
+    // are left as text.
+    fn read_reference(&self, text: &str) -> Result<Option<(&Value, usize)>, CerealError> {
+        let name_length = identifier_length(text);
+        let Some(mut value) = self.get_variable(&text[..name_length]).filter(|_| name_length > 0) else {
+            return Ok(None);
+        };
+        let mut length = name_length;
+
+        loop {
+            let rest = &text[length..];
+            if let Some(field) = rest.strip_prefix('.') {
+                let field_length = identifier_length(field);
+                match value {
+                    Value::Map(entries) if field_length > 0 => match entries.get(&field[..field_length]) {
+                        Some(entry) => {
+                            value = entry;
+                            length += 1 + field_length;
+                        }
+                        None => break,
+                    },
+                    _ => break,
+                }
+            } else if let Some(index) = rest.strip_prefix('[') {
+                // The index may itself use variables, e.g. $hosts[$i]
+                let Some(end) = index.find(']') else { break };
+                let key = self.expand_variables(&index[..end])?;
+                match value.get(key.trim()) {
+                    Some(item) => {
+                        value = item;
+                        length += end + 2;
+                    }
+                    None => break,
+                }
+            } else {
+                break;
+            }
         }
+        Ok(Some((value, length)))
     }
 
     // Add these methods to access the VM
@@ -125,6 +358,12 @@ impl<'a> ExecutionContext<'a> {
     }
 }
 
+impl Default for ExecutionContext<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct MultiCommand {
     commands: Vec<Box<dyn Command>>,
 }
@@ -136,15 +375,20 @@ impl MultiCommand {
 }
 
 impl Command for MultiCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
+    fn name(&self) -> &str {
+        "MULTI"
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
         for command in &self.commands {
-            command.execute(context)?;
+            command.compile(code);
         }
-        Ok(())
     }
 
-    fn name(&self) -> &str {
-        "MULTI"
+    // One line per command
+    fn to_source(&self) -> String {
+        let lines: Vec<String> = self.commands.iter().map(|command| command.to_source()).collect();
+        lines.join("\n")
     }
 
     fn box_clone(&self) -> Box<dyn Command> {
@@ -152,4 +396,22 @@ impl Command for MultiCommand {
             commands: self.commands.iter().map(|cmd| cmd.box_clone()).collect()
         })
     }
-} 
\ No newline at end of file
+} 
+// Length of the variable name at the start of the text
+fn identifier_length(text: &str) -> usize {
+    text.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(text.len())
+}
+
+// Position of the '}' that closes a ${, allowing nested braces
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (position, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(position),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
@@ -1,52 +1,39 @@
-use crate::command::{Command, ExecutionContext};
+use crate::command::Command;
+use crate::bytecode::Op;
+use crate::value::{self, Operand};
+
+/// Exit status used when ABORT is not given one
+pub const DEFAULT_ABORT_CODE: i32 = 1;
 
 pub struct AbortCommand {
     error: String,
+    code: i32,      // Exit status reported when the abort reaches the top level
 }
 
 impl AbortCommand {
-    pub fn new(error: String) -> Self {
-        Self { error }
+    pub fn new(error: String, code: i32) -> Self {
+        Self { error, code }
     }
 }
 
 impl Command for AbortCommand {
-    fn execute(&self, _context: &mut ExecutionContext) -> Result<(), String> {
-        eprintln!("ABORT: {}", self.error);
-        std::process::exit(0);
-    }
-    
     fn name(&self) -> &'static str {
         "ABORT"
     }
 
-    fn is_control_flow(&self) -> bool {
-        false
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(Operand::from_text(&self.error)));
+        code.push(Op::Abort(self.code));
     }
 
-    fn box_clone(&self) -> Box<dyn Command> {
-        Box::new(AbortCommand::new(self.error.clone()))
-    }
-}
-
-pub struct EndIfCommand;
-
-impl Command for EndIfCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        // Clear any skip flags
-        context.clear_skip();
-        Ok(())
-    }
-
-    fn name(&self) -> &'static str {
-        "ENDIF"
-    }
-
-    fn is_control_flow(&self) -> bool {
-        true
+    fn to_source(&self) -> String {
+        match self.code {
+            DEFAULT_ABORT_CODE => format!("ABORT {}", value::quote(&self.error)),
+            code => format!("ABORT {} {}", value::quote(&self.error), code),
+        }
     }
 
     fn box_clone(&self) -> Box<dyn Command> {
-        Box::new(EndIfCommand)
+        Box::new(AbortCommand::new(self.error.clone(), self.code))
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,90 @@
+use crate::command::{Command, ExecutionContext};
+use crate::number::Number;
+use crate::error::CerealError;
+use crate::bytecode::Op;
+use crate::value::{self, Operand, Value};
+
+/// The arithmetic commands, as used by their bytecode instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl Operator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operator::Add => "ADD",
+            Operator::Sub => "SUB",
+            Operator::Mul => "MUL",
+            Operator::Div => "DIV",
+            Operator::Mod => "MOD",
+        }
+    }
+
+    /// Combines two values, reporting which command needed them if they aren't numbers
+    pub fn apply(self, left: &Value, right: &Value) -> Result<Number, CerealError> {
+        let number = |value: &Value| value.to_number().map_err(|e| CerealError::runtime(e).context(self.name()));
+        let (left, right) = (number(left)?, number(right)?);
+        match self {
+            Operator::Add => left.checked_add(right),
+            Operator::Sub => left.checked_sub(right),
+            Operator::Mul => left.checked_mul(right),
+            Operator::Div => left.checked_div(right),
+            Operator::Mod => left.checked_rem(right),
+        }.map_err(|e| CerealError::runtime(e).context(self.name()))
+    }
+
+    /// Combines a variable with a value, storing the result back in the variable
+    pub fn update(self, context: &mut ExecutionContext, target: &str, operand: &Value) -> Result<(), CerealError> {
+        let current = context.get_variable(target).ok_or_else(|| {
+            CerealError::runtime(format!("{}: Variable '{}' is not defined", self.name(), target))
+        })?;
+        let result = self.apply(current, operand)?;
+        context.assign(target, Value::Number(result));
+        Ok(())
+    }
+}
+
+pub struct ArithmeticCommand {
+    operator: Operator,
+    target: String,         // Name of the variable that receives the result
+    operands: Vec<String>,  // One value to combine with the target, or two values to combine
+}
+
+impl ArithmeticCommand {
+    pub fn new(operator: Operator, target: String, operands: Vec<String>) -> Self {
+        Self { operator, target, operands }
+    }
+}
+
+impl Command for ArithmeticCommand {
+    fn name(&self) -> &str {
+        self.operator.name()
+    }
+
+    // With a single operand the target variable itself is the left-hand side
+    fn compile(&self, code: &mut Vec<Op>) {
+        for operand in &self.operands {
+            code.push(Op::Load(Operand::from_text(operand)));
+        }
+        if self.operands.len() == 1 {
+            code.push(Op::Update(self.operator, self.target.clone()));
+        } else {
+            code.push(Op::Arithmetic(self.operator));
+            code.push(Op::Store(self.target.clone()));
+        }
+    }
+
+    fn to_source(&self) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|operand| value::quote(operand)).collect();
+        format!("{} {} {}", self.operator.name(), self.target, operands.join(" "))
+    }
+
+    fn box_clone(&self) -> Box<dyn Command> {
+        Box::new(ArithmeticCommand::new(self.operator, self.target.clone(), self.operands.clone()))
+    }
+}
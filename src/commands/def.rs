@@ -1,28 +1,32 @@
-use crate::command::{Command, ExecutionContext};
+use crate::command::Command;
+use crate::value::Operand;
+use crate::bytecode::Op;
 
 pub struct DefCommand {
     name: String,    // Name of the variable
-    value: String,   // Value to assign
+    value: Operand,  // Value to assign
 }
 
 impl DefCommand {
-    pub fn new(name: String, value: String) -> Self {
+    pub fn new(name: String, value: Operand) -> Self {
         Self { name, value }
     }
 }
 
 impl Command for DefCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        // Expand any variables in the value before assigning
-        let expanded_value = context.expand_variables(&self.value);
-        context.variables.insert(self.name.clone(), expanded_value);
-        Ok(())
-    }
-
     fn name(&self) -> &'static str {
         "DEF"
     }
 
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(self.value.clone()));
+        code.push(Op::Store(self.name.clone()));
+    }
+
+    fn to_source(&self) -> String {
+        format!("DEF {} {}", self.name, self.value.to_source())
+    }
+
     fn box_clone(&self) -> Box<dyn Command> {
         Box::new(DefCommand::new(self.name.clone(), self.value.clone()))
     }
@@ -0,0 +1,33 @@
+use crate::command::Command;
+use crate::source::SourceLocation;
+use crate::bytecode::Op;
+use crate::compiler;
+
+pub struct DeferCommand {
+    command: Box<dyn Command>,  // Statement to run when the script or function call ends
+    location: SourceLocation,   // Where the statement was written, for reporting its errors
+}
+
+impl DeferCommand {
+    pub fn new(command: Box<dyn Command>, location: SourceLocation) -> Self {
+        Self { command, location }
+    }
+}
+
+impl Command for DeferCommand {
+    fn name(&self) -> &'static str {
+        "DEFER"
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Defer(compiler::compile_command(self.command.as_ref(), &self.location)));
+    }
+
+    fn to_source(&self) -> String {
+        format!("DEFER {}", self.command.to_source())
+    }
+
+    fn box_clone(&self) -> Box<dyn Command> {
+        Box::new(DeferCommand::new(self.command.box_clone(), self.location.clone()))
+    }
+}
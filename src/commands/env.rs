@@ -0,0 +1,41 @@
+use crate::command::Command;
+use crate::bytecode::Op;
+use crate::value::{self, Operand};
+
+pub struct EnvCommand {
+    name: String,           // Environment variable to change
+    value: Option<String>,  // New value, or None to remove the variable
+}
+
+impl EnvCommand {
+    pub fn new(name: String, value: Option<String>) -> Self {
+        Self { name, value }
+    }
+}
+
+impl Command for EnvCommand {
+    fn name(&self) -> &'static str {
+        "ENV"
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        match &self.value {
+            Some(value) => {
+                code.push(Op::Load(Operand::from_text(value)));
+                code.push(Op::SetEnv(self.name.clone()));
+            }
+            None => code.push(Op::RemoveEnv(self.name.clone())),
+        }
+    }
+
+    fn to_source(&self) -> String {
+        match &self.value {
+            Some(value) => format!("ENV {} {}", self.name, value::quote(value)),
+            None => format!("ENV {}", self.name),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Command> {
+        Box::new(EnvCommand::new(self.name.clone(), self.value.clone()))
+    }
+}
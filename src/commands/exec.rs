@@ -1,5 +1,8 @@
 use std::process::Command as ProcessCommand;
 use crate::command::{Command, ExecutionContext};
+use crate::error::CerealError;
+use crate::bytecode::Op;
+use crate::value::{self, Operand};
 
 pub struct ExecCommand {
     cmd: String,    // Command to execute
@@ -9,26 +12,22 @@ impl ExecCommand {
     pub fn new(cmd: String) -> Self {
         Self { cmd }
     }
-}
-
-impl Command for ExecCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        // Expand variables in the command
-        let expanded_cmd = context.expand_variables(&self.cmd);
 
+    /// Runs an expanded command in the shell
+    pub fn run(context: &mut ExecutionContext, expanded_cmd: &str) -> Result<(), CerealError> {
         // Execute the command using the appropriate shell
         let output = if cfg!(windows) {
             ProcessCommand::new("cmd")
                 .arg("/C")
-                .arg(&expanded_cmd)
+                .arg(expanded_cmd)
                 .output()
-                .map_err(|e| format!("Failed to execute command: {}", e))?
+                .map_err(|e| CerealError::io(format!("Failed to execute command: {}", e)))?
         } else {
             ProcessCommand::new("sh")
                 .arg("-c")
-                .arg(&expanded_cmd)
+                .arg(expanded_cmd)
                 .output()
-                .map_err(|e| format!("Failed to execute command: {}", e))?
+                .map_err(|e| CerealError::io(format!("Failed to execute command: {}", e)))?
         };
 
         // Handle command output
@@ -49,18 +48,29 @@ impl Command for ExecCommand {
         if !output.status.success() {
             // Set the status before returning the error
             context.set_variable("exec_status".to_string(), output.status.to_string());
-            return Err(format!("Command failed with exit code: {}", output.status));
+            return Err(CerealError::runtime(format!("Command failed with exit code: {}", output.status)));
         }
 
         context.set_variable("exec_status".to_string(), output.status.to_string());
 
         Ok(())
     }
+}
 
+impl Command for ExecCommand {
     fn name(&self) -> &'static str {
         "EXEC"
     }
 
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(Operand::from_text(&self.cmd)));
+        code.push(Op::Exec);
+    }
+
+    fn to_source(&self) -> String {
+        format!("EXEC {}", value::quote(&self.cmd))
+    }
+
     fn box_clone(&self) -> Box<dyn Command> {
         Box::new(ExecCommand::new(self.cmd.clone()))
     }
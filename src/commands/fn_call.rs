@@ -1,28 +1,47 @@
 use crate::command::Command;
-use crate::command::ExecutionContext;
+use crate::value::Operand;
+use crate::bytecode::Op;
 
 pub struct FnCallCommand {
     name: String,
+    args: Vec<Operand>,
+    result_var: Option<String>,  // Variable that receives the RETURN value
 }
 
 impl FnCallCommand {
-    pub fn new(name: String) -> Self {
-        FnCallCommand { name }
+    pub fn new(name: String, args: Vec<Operand>, result_var: Option<String>) -> Self {
+        FnCallCommand { name, args, result_var }
     }
 }
 
 impl Command for FnCallCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        context.get_vm().call_function(&self.name)
-    }
-
     fn name(&self) -> &str {
         "CALL"
     }
 
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.extend(self.args.iter().map(|arg| Op::Load(arg.clone())));
+        code.push(Op::Call(self.name.clone(), self.args.len()));
+        code.push(match &self.result_var {
+            Some(var) => Op::Store(var.clone()),
+            None => Op::Pop,
+        });
+    }
+
+    fn to_source(&self) -> String {
+        let mut words = vec!["CALL".to_string(), self.name.clone()];
+        words.extend(self.args.iter().map(Operand::to_source));
+        if let Some(var) = &self.result_var {
+            words.push(format!("-> {}", var));
+        }
+        words.join(" ")
+    }
+
     fn box_clone(&self) -> Box<dyn Command> {
         Box::new(Self {
             name: self.name.clone(),
+            args: self.args.clone(),
+            result_var: self.result_var.clone(),
         })
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,37 @@
+use crate::command::{Command, ExecutionContext};
+use crate::bytecode::Op;
+
+pub struct GlobalCommand {
+    names: Vec<String>,   // Variables that refer to the global scope
+}
+
+impl GlobalCommand {
+    pub fn new(names: Vec<String>) -> Self {
+        Self { names }
+    }
+
+    /// Outside of a function everything is already global, so this does nothing
+    pub fn run(context: &mut ExecutionContext, names: &[String]) {
+        for name in names {
+            context.declare_global(name);
+        }
+    }
+}
+
+impl Command for GlobalCommand {
+    fn name(&self) -> &'static str {
+        "GLOBAL"
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Global(self.names.clone()));
+    }
+
+    fn to_source(&self) -> String {
+        format!("GLOBAL {}", self.names.join(" "))
+    }
+
+    fn box_clone(&self) -> Box<dyn Command> {
+        Box::new(GlobalCommand::new(self.names.clone()))
+    }
+}
@@ -1,5 +1,7 @@
 use crate::command::{Command, ExecutionContext};
 use std::io::{self, Write};
+use crate::error::CerealError;
+use crate::bytecode::Op;
 
 pub struct InputCommand {
     var: String
@@ -9,31 +11,39 @@ impl InputCommand {
     pub fn new(var: String) -> Self {
         InputCommand { var }
     }
-}
 
-impl Command for InputCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
+    pub fn run(context: &mut ExecutionContext, var: &str) -> Result<(), CerealError> {
         // Flush stdout to ensure prompt is displayed before input
-        io::stdout().flush().map_err(|e| e.to_string())?;
+        io::stdout().flush()?;
 
         let mut input = String::new();
         io::stdin()
             .read_line(&mut input)
-            .map_err(|e| e.to_string())?;
+            ?;
         
         // Remove trailing newline
         input = input.trim().to_string();
         
         // Store the input in the VM's variables
-        context.set_variable(self.var.to_string(), input);
+        context.set_variable(var.to_string(), input);
         
         Ok(())
     }
+}
 
+impl Command for InputCommand {
     fn name(&self) -> &'static str {
         "INPUT"
     }
 
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Input(self.var.clone()));
+    }
+
+    fn to_source(&self) -> String {
+        format!("INPUT {}", self.var)
+    }
+
     fn box_clone(&self) -> Box<dyn Command> {
         Box::new(InputCommand { var: self.var.clone() })
     }
@@ -1,6 +1,5 @@
 use crate::command::Command;
-use crate::command::ExecutionContext;
-use crate::libraries::LibraryExecutor;
+use crate::bytecode::Op;
 
 pub struct LibCallCommand {
     name: String,
@@ -13,18 +12,16 @@ impl LibCallCommand {
 }
 
 impl Command for LibCallCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        let res = LibraryExecutor::new().execute(&self.name, context);
-
-        if let Err(e) = res {
-            return Err(e);
-        }
+    fn name(&self) -> &str {
+        "LIBCALL"
+    }
 
-        Ok(())
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::LibCall(self.name.clone()));
     }
 
-    fn name(&self) -> &str {
-        "LIBCALL"
+    fn to_source(&self) -> String {
+        format!("LIBCALL {}", self.name)
     }
 
     fn box_clone(&self) -> Box<dyn Command> {
@@ -1,24 +1,28 @@
 // Re-export all commands
 mod def;
 mod exec;
-mod if_cmd;
 mod print;
-mod fn_def;
 mod fn_call;
-mod endfn;
 mod input_cmd;
 mod mov;
 mod abort;
 mod lib_call;
+mod return_cmd;
+mod global;
+mod arithmetic;
+mod defer;
+mod env;
 pub use def::DefCommand;
 pub use exec::ExecCommand;
-pub use if_cmd::{IfCommand, EndIfCommand};
 pub use print::PrintCommand;
-pub use fn_def::FnDefCommand;
 pub use fn_call::FnCallCommand;
-pub use endfn::EndFnCommand;
 pub use input_cmd::InputCommand;
 pub use lib_call::LibCallCommand;
 pub use mov::MovCommand;
-pub use abort::AbortCommand;
+pub use abort::{AbortCommand, DEFAULT_ABORT_CODE};
+pub use return_cmd::ReturnCommand;
+pub use global::GlobalCommand;
+pub use arithmetic::{ArithmeticCommand, Operator};
+pub use defer::DeferCommand;
+pub use env::EnvCommand;
 pub mod registry;
@@ -1,28 +1,31 @@
-use crate::command::{Command, ExecutionContext};
+use crate::command::Command;
+use crate::value::Operand;
+use crate::bytecode::Op;
 
 pub struct MovCommand {
     name: String,    // Name of the variable
-    value: String,   // Value to assign
+    value: Operand,  // Value to assign
 }
 
 impl MovCommand {
-    pub fn new(name: String, value: String) -> Self {
+    pub fn new(name: String, value: Operand) -> Self {
         Self { name, value }
     }
 }
 
 impl Command for MovCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        // Expand any variables in the value before assigning
-        let expanded_value = context.expand_variables(&self.value);
-        context.set_variable(self.name.clone(), expanded_value);
-        Ok(())
-    }
-
     fn name(&self) -> &'static str {
         "MOV"
     }
 
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Move(self.name.clone(), self.value.clone()));
+    }
+
+    fn to_source(&self) -> String {
+        format!("MOV {} {}", self.name, self.value.to_source())
+    }
+
     fn box_clone(&self) -> Box<dyn Command> {
         Box::new(MovCommand::new(self.name.clone(), self.value.clone()))
     }
@@ -1,4 +1,6 @@
-use crate::command::{Command, ExecutionContext};
+use crate::command::Command;
+use crate::bytecode::Op;
+use crate::value::{self, Operand};
 
 pub struct PrintCommand {
     cmd: String,
@@ -11,27 +13,17 @@ impl PrintCommand {
 }
 
 impl Command for PrintCommand {
-    fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        let expanded_cmd = context.expand_variables(&self.cmd);
-
-        if expanded_cmd.contains("$") {
-            let variable_name = expanded_cmd.split('$').collect::<Vec<&str>>();
-            let variable_value = context.variables.get(&variable_name[1].to_string());
+    fn name(&self) -> &'static str {
+        "PRINT"
+    }
 
-            if let Some(value) = variable_value {
-                println!("{}", value);
-            } else {
-                return Err(format!("Variable {} not found", variable_name[1]));
-            }
-        } else {
-            println!("{}", expanded_cmd);
-        }
-        
-        Ok(())
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(Operand::from_text(&self.cmd)));
+        code.push(Op::Print);
     }
 
-    fn name(&self) -> &'static str {
-        "PRINT"
+    fn to_source(&self) -> String {
+        format!("PRINT {}", value::quote(&self.cmd))
     }
 
     fn box_clone(&self) -> Box<dyn Command> {
@@ -1,14 +1,42 @@
 use std::collections::HashMap;
 use crate::command::Command;
-use crate::commands::{DefCommand, ExecCommand, IfCommand, EndIfCommand, PrintCommand, AbortCommand};
+use crate::lexer::{Token, TokenType};
+use crate::error::CerealError;
+use crate::value::Operand;
+use crate::commands::{ArithmeticCommand, Operator, DefCommand, GlobalCommand, ExecCommand, PrintCommand, AbortCommand, DEFAULT_ABORT_CODE, EnvCommand};
 
+// Signature of the functions that build a command from its arguments
+type CommandFactory = fn(Vec<&str>) -> Result<Box<dyn Command>, CerealError>;
+
+// Signature of the functions that build a command from its argument tokens, for commands
+// that need to tell keywords and symbols apart from string literals with the same text
+type TokenCommandFactory = fn(&[Token]) -> Result<Box<dyn Command>, CerealError>;
+
+#[derive(Clone, Copy)]
+enum FactoryFn {
+    Values(CommandFactory),
+    Tokens(TokenCommandFactory),
+}
+
+// Builds an arithmetic command in format: <OP> <variable> <value> or <OP> <variable> <a> <b>
+fn arithmetic(operator: Operator, args: Vec<&str>) -> Result<Box<dyn Command>, CerealError> {
+    if args.len() != 2 && args.len() != 3 {
+        let name = operator.name();
+        return Err(CerealError::parse(format!("{} must be in format: {} <variable> <value> or {} <variable> <a> <b>", name, name, name)));
+    }
+    Ok(Box::new(ArithmeticCommand::new(
+        operator,
+        args[0].to_string(),
+        args[1..].iter().map(|arg| arg.to_string()).collect(),
+    )))
+}
 
 // Create a wrapper struct that implements Clone
 #[derive(Clone)]
 struct CloneableFactory {
     #[allow(dead_code)]
     name: String,
-    create_fn: fn(Vec<&str>) -> Result<Box<dyn Command>, String>,
+    create_fn: FactoryFn,
 }
 
 #[derive(Clone)]
@@ -23,48 +51,82 @@ impl CommandRegistry {
         };
         
         // Register built-in commands
-        registry.register("DEF", "DEF", |args| {
+        registry.register_with_tokens("DEF", "DEF", |args| {
             if args.len() < 2 {
-                return Err("DEF requires variable name and value".to_string());
+                return Err(CerealError::parse("DEF requires variable name and value"));
             }
             Ok(Box::new(DefCommand::new(
-                args[0].to_string(),
-                args[1..].join(" "),
+                args[0].value.clone(),
+                Operand::from_tokens(&args[1..])?,
             )))
         });
 
+        registry.register("GLOBAL", "GLOBAL", |args| {
+            if args.is_empty() {
+                return Err(CerealError::parse("GLOBAL requires at least one variable name"));
+            }
+            Ok(Box::new(GlobalCommand::new(args.iter().map(|name| name.to_string()).collect())))
+        });
+
         registry.register("EXEC", "EXEC", |args| {
             if args.is_empty() {
-                return Err("EXEC requires a command".to_string());
+                return Err(CerealError::parse("EXEC requires a command"));
             }
             let cmd = args.join(" ");
             println!("Creating EXEC command with: {}", cmd);
             Ok(Box::new(ExecCommand::new(cmd)))
         });
 
-        registry.register("IF", "IF", |args| {
-            if args.len() != 3 || (args[1] != "IS" && args[1] != "NOT" && args[1] != "CONTAINS" && args[1] != "NOTCONTAINS") {
-                return Err("IF requires a condition variable".to_string());
-            }
-            Ok(Box::new(IfCommand::new(args[2].to_string(), args[0].to_string(), args[1].to_string())))
-        });
-
-        registry.register("ENDIF", "ENDIF", |_| {
-            Ok(Box::new(EndIfCommand))
-        });
+        registry.register("ADD", "ADD", |args| arithmetic(Operator::Add, args));
+        registry.register("SUB", "SUB", |args| arithmetic(Operator::Sub, args));
+        registry.register("MUL", "MUL", |args| arithmetic(Operator::Mul, args));
+        registry.register("DIV", "DIV", |args| arithmetic(Operator::Div, args));
+        registry.register("MOD", "MOD", |args| arithmetic(Operator::Mod, args));
 
-        registry.register("ABORT", "ABORT", |args| {
-            Ok(Box::new(AbortCommand::new(args.join(" "))))
+        // ABORT <message> [code], where a trailing whole number after the message is the exit status
+        registry.register_with_tokens("ABORT", "ABORT", |args| {
+            let (message, code) = match args.split_last() {
+                Some((last, rest)) if !rest.is_empty() && last.token_type == TokenType::Number => {
+                    let code = last.value.parse::<i32>().map_err(|_| {
+                        CerealError::parse(format!("ABORT exit code must be a whole number, got '{}'", last.value))
+                    })?;
+                    (rest, code)
+                }
+                _ => (args, DEFAULT_ABORT_CODE),
+            };
+            let message: Vec<&str> = message.iter().map(|token| token.value.as_str()).collect();
+            Ok(Box::new(AbortCommand::new(message.join(" "), code)))
         });
 
         registry.register("PRINT", "PRINT", |args| {
             Ok(Box::new(PrintCommand::new(args.join(" "))))
         });
+
+        // ENV <name> <value> sets an environment variable, ENV <name> removes it
+        registry.register_with_tokens("ENV", "ENV", |args| {
+            let Some((name, value)) = args.split_first() else {
+                return Err(CerealError::parse("ENV must be in format: ENV <name> [value]"));
+            };
+            if name.token_type != TokenType::Identifier {
+                return Err(CerealError::parse(format!("ENV: invalid environment variable name '{}'", name.value)));
+            }
+            let value: Vec<&str> = value.iter().map(|token| token.value.as_str()).collect();
+            let value = (!value.is_empty()).then(|| value.join(" "));
+            Ok(Box::new(EnvCommand::new(name.value.clone(), value)))
+        });
         
         registry
     }
 
-    pub fn register(&mut self, name: &str, factory_name: &str, factory: fn(Vec<&str>) -> Result<Box<dyn Command>, String>) {
+    pub fn register(&mut self, name: &str, factory_name: &str, factory: CommandFactory) {
+        self.insert(name, factory_name, FactoryFn::Values(factory));
+    }
+
+    pub fn register_with_tokens(&mut self, name: &str, factory_name: &str, factory: TokenCommandFactory) {
+        self.insert(name, factory_name, FactoryFn::Tokens(factory));
+    }
+
+    fn insert(&mut self, name: &str, factory_name: &str, factory: FactoryFn) {
         self.factories.insert(
             name.to_uppercase(),
             CloneableFactory {
@@ -74,12 +136,23 @@ impl CommandRegistry {
         );
     }
 
-    pub fn create_command(&self, name: &str, args: Vec<&str>) -> Result<Box<dyn Command>, String> {
+    pub fn create_command(&self, name: &str, args: &[Token]) -> Result<Box<dyn Command>, CerealError> {
         if let Some(factory) = self.factories.get(&name.to_uppercase()) {
-            let command_args = args.to_vec();
-            (factory.create_fn)(command_args)
+            match factory.create_fn {
+                FactoryFn::Values(create_fn) => {
+                    let command_args = args.iter().map(|t| t.value.as_str()).collect();
+                    create_fn(command_args)
+                }
+                FactoryFn::Tokens(create_fn) => create_fn(args),
+            }
         } else {
-            Err(format!("Unknown command: {}", name))
+            Err(CerealError::unknown_command(name))
         }
     }
-} 
\ No newline at end of file
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
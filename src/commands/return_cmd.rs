@@ -0,0 +1,32 @@
+use crate::command::Command;
+use crate::value::Operand;
+use crate::bytecode::Op;
+
+pub struct ReturnCommand {
+    value: Operand,  // Value to return to the caller
+}
+
+impl ReturnCommand {
+    pub fn new(value: Operand) -> Self {
+        Self { value }
+    }
+}
+
+impl Command for ReturnCommand {
+    fn name(&self) -> &'static str {
+        "RETURN"
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(self.value.clone()));
+        code.push(Op::Ret);
+    }
+
+    fn to_source(&self) -> String {
+        format!("RETURN {}", self.value.to_source())
+    }
+
+    fn box_clone(&self) -> Box<dyn Command> {
+        Box::new(ReturnCommand::new(self.value.clone()))
+    }
+}
@@ -0,0 +1,266 @@
+use crate::ast::{Catch, Statement, StatementKind};
+use crate::bytecode::{Chunk, Op};
+use crate::command::Command;
+use crate::expression::Expression;
+use crate::source::SourceLocation;
+use crate::value::{Operand, Value};
+
+/// A loop or TRY block around the statement being compiled, which BREAK, CONTINUE and RETURN leave
+enum Enclosing<'a> {
+    Loop {
+        // Where CONTINUE jumps to
+        next: usize,
+        // BREAK jumps that go to the end of the loop, which isn't known yet
+        breaks: Vec<usize>,
+    },
+    // A TRY block whose handler is in place, and the FINALLY to run when leaving it early
+    Try(Option<&'a [Statement]>),
+}
+
+/// Turns parsed statements into bytecode. Blocks become jumps, and leaving a TRY block early
+/// with BREAK, CONTINUE or RETURN runs a copy of its FINALLY on the way out.
+pub struct Compiler<'a> {
+    chunk: Chunk,
+    enclosing: Vec<Enclosing<'a>>,
+    // Location of the statement being compiled
+    location: SourceLocation,
+}
+
+impl<'a> Compiler<'a> {
+    /// Compiles statements into a chunk, e.g. the top level of a script or the body of a function
+    pub fn compile(statements: &'a [Statement]) -> Chunk {
+        let mut compiler = Compiler {
+            chunk: Chunk::new(),
+            enclosing: Vec::new(),
+            location: SourceLocation::new("<input>", Default::default(), ""),
+        };
+        compiler.block(statements);
+        compiler.chunk
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.push(op, &self.location)
+    }
+
+    /// Position of the next instruction
+    fn here(&self) -> usize {
+        self.chunk.ops.len()
+    }
+
+    fn block(&mut self, statements: &'a [Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        let outer = std::mem::replace(&mut self.location, statement.location.clone());
+
+        match &statement.kind {
+            StatementKind::Command(command) => {
+                let mut ops = Vec::new();
+                command.compile(&mut ops);
+                for op in ops {
+                    // RETURN leaves every TRY block it is in, running their FINALLY first
+                    if op == Op::Ret {
+                        self.leave(0);
+                    }
+                    self.emit(op);
+                }
+            }
+            StatementKind::If { branches, otherwise } => {
+                let mut ends = Vec::new();
+                for (condition, body) in branches {
+                    self.condition(condition);
+                    let skip = self.emit(Op::JumpIfFalse(0));
+                    self.block(body);
+                    ends.push(self.emit(Op::Jump(0)));
+                    let next = self.here();
+                    self.chunk.patch(skip, next);
+                }
+                if let Some(body) = otherwise {
+                    self.block(body);
+                }
+                self.patch_all(&ends);
+            }
+            StatementKind::While { condition, body } => {
+                let start = self.here();
+                self.condition(condition);
+                let exit = self.emit(Op::JumpIfFalse(0));
+                let breaks = self.loop_body(start, body);
+                self.emit(Op::Jump(start));
+                self.patch_all(&breaks);
+                self.patch_all(&[exit]);
+            }
+            StatementKind::For { var, source, mode, body } => {
+                self.emit(Op::Items(mode.clone(), source.clone()));
+                let next = self.emit(Op::Next(var.clone(), 0));
+                let breaks = self.loop_body(next, body);
+                self.emit(Op::Jump(next));
+                self.patch_all(&breaks);
+                self.patch_all(&[next]);
+                // Drop the items once the loop is done
+                self.emit(Op::Pop);
+            }
+            StatementKind::Try { body, catch, finally } => self.try_block(body, catch.as_ref(), finally.as_deref()),
+            StatementKind::Break => {
+                if let Some(level) = self.innermost_loop() {
+                    self.leave(level + 1);
+                    let jump = self.emit(Op::Jump(0));
+                    if let Enclosing::Loop { breaks, .. } = &mut self.enclosing[level] {
+                        breaks.push(jump);
+                    }
+                }
+            }
+            StatementKind::Continue => {
+                if let Some(level) = self.innermost_loop() {
+                    self.leave(level + 1);
+                    if let Enclosing::Loop { next, .. } = self.enclosing[level] {
+                        self.emit(Op::Jump(next));
+                    }
+                }
+            }
+            // Functions and imports are loaded by the VM before anything runs
+            StatementKind::Function { .. } | StatementKind::Import { .. } => {}
+        }
+
+        self.location = outer;
+    }
+
+    /// Compiles a condition into instructions that push whether it holds. The operands of each
+    /// comparison are loaded first, and the right side of AND/OR is skipped when it isn't needed.
+    fn condition(&mut self, condition: &Expression) {
+        match condition {
+            Expression::Compare { left, operator, right } => {
+                self.emit(Op::Load(Operand::from_text(left)));
+                self.emit(Op::Load(Operand::from_text(right)));
+                self.emit(Op::Compare(*operator));
+            }
+            Expression::Not(inner) => {
+                self.condition(inner);
+                self.emit(Op::Not);
+            }
+            Expression::And(left, right) => {
+                self.condition(left);
+                let skip = self.emit(Op::JumpIfFalse(0));
+                self.condition(right);
+                let end = self.emit(Op::Jump(0));
+                self.patch_all(&[skip]);
+                self.emit(Op::Load(Operand::Literal(Value::Bool(false))));
+                self.patch_all(&[end]);
+            }
+            Expression::Or(left, right) => {
+                self.condition(left);
+                let next = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Load(Operand::Literal(Value::Bool(true))));
+                let end = self.emit(Op::Jump(0));
+                self.patch_all(&[next]);
+                self.condition(right);
+                self.patch_all(&[end]);
+            }
+        }
+    }
+
+    /// Compiles the body of a loop, returning the BREAK jumps to point at the end of the loop
+    fn loop_body(&mut self, next: usize, body: &'a [Statement]) -> Vec<usize> {
+        self.enclosing.push(Enclosing::Loop { next, breaks: Vec::new() });
+        self.block(body);
+        match self.enclosing.pop() {
+            Some(Enclosing::Loop { breaks, .. }) => breaks,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Compiles a TRY block. Errors in the body jump to CATCH, and errors in CATCH (or that CATCH
+    /// doesn't handle) jump to a copy of FINALLY that raises the error again once it has run.
+    fn try_block(&mut self, body: &'a [Statement], catch: Option<&'a Catch>, finally: Option<&'a [Statement]>) {
+        if catch.is_none() && finally.is_none() {
+            self.block(body);
+            return;
+        }
+
+        let handler = self.emit(Op::Try(0));
+        self.guarded(body, finally);
+        let mut done = vec![self.emit(Op::Jump(0))];
+        let mut cleanup = vec![handler];
+
+        if let Some(catch) = catch {
+            let here = self.here();
+            self.chunk.patch(handler, here);
+            cleanup = vec![self.emit(Op::Catch(catch.var.clone(), 0))];
+            cleanup.push(self.emit(Op::Try(0)));
+            self.guarded(&catch.body, finally);
+            done.push(self.emit(Op::Jump(0)));
+        }
+
+        self.patch_all(&cleanup);
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+        self.emit(Op::Rethrow);
+
+        self.patch_all(&done);
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+    }
+
+    /// Compiles statements run with a TRY handler in place, removing the handler after them
+    fn guarded(&mut self, statements: &'a [Statement], finally: Option<&'a [Statement]>) {
+        self.enclosing.push(Enclosing::Try(finally));
+        self.block(statements);
+        self.enclosing.pop();
+        self.emit(Op::EndTry);
+    }
+
+    /// Removes the handlers of the TRY blocks being left, innermost first, running their FINALLY
+    /// statements. Blocks at or outside of `level` are kept.
+    fn leave(&mut self, level: usize) {
+        let mut index = self.enclosing.len();
+        while index > level {
+            index -= 1;
+            if let Enclosing::Try(finally) = self.enclosing[index] {
+                self.emit(Op::EndTry);
+                if let Some(finally) = finally {
+                    // FINALLY runs outside of the blocks being left
+                    let inner = self.enclosing.split_off(index);
+                    self.block(finally);
+                    self.enclosing.extend(inner);
+                }
+            }
+        }
+    }
+
+    fn innermost_loop(&self) -> Option<usize> {
+        self.enclosing.iter().rposition(|enclosing| matches!(enclosing, Enclosing::Loop { .. }))
+    }
+
+    /// Points the given jumps at the next instruction
+    fn patch_all(&mut self, jumps: &[usize]) {
+        let target = self.here();
+        for jump in jumps {
+            self.chunk.patch(*jump, target);
+        }
+    }
+}
+
+/// Compiles a single command into a chunk where every instruction is reported at the given location,
+/// e.g. for a deferred command
+pub fn compile_command(command: &dyn Command, location: &SourceLocation) -> Chunk {
+    let mut ops = Vec::new();
+    command.compile(&mut ops);
+    let mut chunk = Chunk::new();
+    for op in ops {
+        chunk.push(op, location);
+    }
+    chunk
+}
+
+/// Compiles a command run on its own rather than as part of a script, e.g. by `VM::execute_line`.
+/// Its errors are reported without a location, as there is no file to point at.
+pub fn compile_line(command: &dyn Command) -> Chunk {
+    let mut ops = Vec::new();
+    command.compile(&mut ops);
+    Chunk { ops, ..Chunk::new() }
+}
+
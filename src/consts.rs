@@ -14,4 +14,26 @@ impl Registers {
     pub const R8: &'static str = "r8";
     pub const R9: &'static str = "r9";
     pub const R10: &'static str = "r10";
-}
\ No newline at end of file
+}
+/// Variables the VM sets up for scripts
+pub struct ScriptVariables;
+
+#[allow(dead_code)]
+impl ScriptVariables {
+    /// Map of the process environment, e.g. `$env.HOME`
+    pub const ENV: &'static str = "env";
+    /// List of the positional command-line arguments
+    pub const ARGS: &'static str = "args";
+    /// Number of positional command-line arguments
+    pub const ARGC: &'static str = "argc";
+    /// Prefix of the variables holding each positional argument, starting at `$arg1`
+    pub const ARG_PREFIX: &'static str = "arg";
+
+    /// Whether the VM sets a variable with this name, e.g. `args` or `arg2`
+    pub fn is_reserved(name: &str) -> bool {
+        let numbered = name
+            .strip_prefix(Self::ARG_PREFIX)
+            .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+        numbered || [Self::ENV, Self::ARGS, Self::ARGC].contains(&name)
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::BTreeMap;
+use crate::ast::ForMode;
+use crate::bytecode::{Chunk, Op};
+use crate::commands::Operator;
+use crate::error::CerealError;
+use crate::expression::Comparison;
+use crate::number::Number;
+use crate::source::SourceLocation;
+use crate::value::{Operand, Value};
+use crate::vm::{CompiledProgram, Function};
+
+/// Bytes every compiled file starts with
+pub const MAGIC: &[u8; 4] = b"CRLC";
+
+/// Version of the layout written after the header. Bump it whenever an encoding below changes,
+/// so files built by an older VM are rejected instead of being misread.
+pub const FORMAT_VERSION: u16 = 1;
+
+// Magic, version and checksum
+const HEADER_LENGTH: usize = 4 + 2 + 4;
+
+/// Checks whether the bytes of a file look like a compiled program rather than a script
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Writes a compiled program as `CRLC`, the format version and a checksum of the rest of the file,
+/// followed by the program itself. Numbers are little-endian and lengths are written before
+/// the strings and lists they belong to.
+pub fn encode(program: &CompiledProgram) -> Vec<u8> {
+    let mut body = Writer::default();
+    program.encode(&mut body);
+
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + body.bytes.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&body.bytes).to_le_bytes());
+    bytes.extend_from_slice(&body.bytes);
+    bytes
+}
+
+/// Reads a program written by `encode`, checking its header and checksum first
+pub fn decode(bytes: &[u8]) -> Result<CompiledProgram, CerealError> {
+    if !is_compiled(bytes) {
+        return Err(CerealError::io("Not a compiled Cereal program"));
+    }
+    if bytes.len() < HEADER_LENGTH {
+        return Err(CerealError::io("Compiled program is truncated"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(CerealError::io(format!(
+            "Compiled program has format version {}, but this VM reads version {}; rebuild it with `cereal build`",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let body = &bytes[HEADER_LENGTH..];
+    if checksum(body) != expected {
+        return Err(CerealError::io("Compiled program is corrupted: checksum mismatch"));
+    }
+
+    let mut reader = Reader { bytes: body, position: 0 };
+    let program = CompiledProgram::decode(&mut reader)?;
+    if reader.position != body.len() {
+        return Err(CerealError::io("Compiled program has unexpected data at the end"));
+    }
+    Ok(program)
+}
+
+/// 32-bit FNV-1a hash, enough to catch files that were truncated or changed by accident
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CerealError> {
+        let bytes = self.bytes
+            .get(self.position..self.position + N)
+            .ok_or_else(|| CerealError::io("Compiled program is truncated"))?;
+        self.position += N;
+        Ok(bytes.try_into().unwrap_or([0; N]))
+    }
+
+    fn u8(&mut self) -> Result<u8, CerealError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CerealError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, CerealError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+}
+
+fn invalid(what: &str, tag: u8) -> CerealError {
+    CerealError::io(format!("Compiled program has an unknown {} tag {}", what, tag))
+}
+
+/// A part of a compiled program that can be written to and read back from a file
+trait Encode: Sized {
+    fn encode(&self, writer: &mut Writer);
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError>;
+}
+
+impl Encode for usize {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u64(*self as u64);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        usize::try_from(reader.u64()?).map_err(|_| CerealError::io("Compiled program has a number that is too large"))
+    }
+}
+
+impl Encode for i32 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u32(*self as u32);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(reader.u32()? as i32)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u8(*self as u8);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(reader.u8()? != 0)
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, writer: &mut Writer) {
+        self.len().encode(writer);
+        writer.bytes.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        let length = usize::decode(reader)?;
+        let end = reader.position.checked_add(length).filter(|end| *end <= reader.bytes.len())
+            .ok_or_else(|| CerealError::io("Compiled program is truncated"))?;
+        let text = std::str::from_utf8(&reader.bytes[reader.position..end])
+            .map_err(|_| CerealError::io("Compiled program has text that isn't UTF-8"))?;
+        reader.position = end;
+        Ok(text.to_string())
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            Some(value) => {
+                writer.u8(1);
+                value.encode(writer);
+            }
+            None => writer.u8(0),
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        match reader.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(T::decode(reader)?)),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, writer: &mut Writer) {
+        self.len().encode(writer);
+        for item in self {
+            item.encode(writer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        // The length isn't trusted to reserve memory, a damaged file runs out of bytes first
+        let length = usize::decode(reader)?;
+        let mut items = Vec::new();
+        for _ in 0..length {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, writer: &mut Writer) {
+        self.0.encode(writer);
+        self.1.encode(writer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+impl Encode for Value {
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            Value::Null => writer.u8(0),
+            Value::Bool(value) => {
+                writer.u8(1);
+                value.encode(writer);
+            }
+            Value::Number(Number::Int(int)) => {
+                writer.u8(2);
+                writer.u64(*int as u64);
+            }
+            Value::Number(Number::Float(float)) => {
+                writer.u8(3);
+                writer.u64(float.to_bits());
+            }
+            Value::String(text) => {
+                writer.u8(4);
+                text.encode(writer);
+            }
+            Value::List(items) => {
+                writer.u8(5);
+                items.encode(writer);
+            }
+            Value::Map(entries) => {
+                writer.u8(6);
+                entries.len().encode(writer);
+                for (key, value) in entries {
+                    key.encode(writer);
+                    value.encode(writer);
+                }
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => Value::Null,
+            1 => Value::Bool(bool::decode(reader)?),
+            2 => Value::Number(Number::Int(reader.u64()? as i64)),
+            3 => Value::Number(Number::Float(f64::from_bits(reader.u64()?))),
+            4 => Value::String(String::decode(reader)?),
+            5 => Value::List(Vec::decode(reader)?),
+            6 => {
+                let entries: Vec<(String, Value)> = Vec::decode(reader)?;
+                Value::Map(entries.into_iter().collect::<BTreeMap<_, _>>())
+            }
+            tag => return Err(invalid("value", tag)),
+        })
+    }
+}
+
+impl Encode for Operand {
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            Operand::Literal(value) => {
+                writer.u8(0);
+                value.encode(writer);
+            }
+            Operand::Text(text) => {
+                writer.u8(1);
+                text.encode(writer);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => Operand::Literal(Value::decode(reader)?),
+            1 => Operand::Text(String::decode(reader)?),
+            tag => return Err(invalid("operand", tag)),
+        })
+    }
+}
+
+impl Encode for Comparison {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u8(match self {
+            Comparison::Is => 0,
+            Comparison::Not => 1,
+            Comparison::Contains => 2,
+            Comparison::NotContains => 3,
+            Comparison::Gt => 4,
+            Comparison::Lt => 5,
+            Comparison::Gte => 6,
+            Comparison::Lte => 7,
+        });
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => Comparison::Is,
+            1 => Comparison::Not,
+            2 => Comparison::Contains,
+            3 => Comparison::NotContains,
+            4 => Comparison::Gt,
+            5 => Comparison::Lt,
+            6 => Comparison::Gte,
+            7 => Comparison::Lte,
+            tag => return Err(invalid("comparison", tag)),
+        })
+    }
+}
+
+impl Encode for Operator {
+    fn encode(&self, writer: &mut Writer) {
+        writer.u8(match self {
+            Operator::Add => 0,
+            Operator::Sub => 1,
+            Operator::Mul => 2,
+            Operator::Div => 3,
+            Operator::Mod => 4,
+        });
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => Operator::Add,
+            1 => Operator::Sub,
+            2 => Operator::Mul,
+            3 => Operator::Div,
+            4 => Operator::Mod,
+            tag => return Err(invalid("arithmetic operator", tag)),
+        })
+    }
+}
+
+impl Encode for ForMode {
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            ForMode::Items => writer.u8(0),
+            ForMode::Lines => writer.u8(1),
+            ForMode::Words => writer.u8(2),
+            ForMode::Split(delimiter) => {
+                writer.u8(3);
+                delimiter.encode(writer);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => ForMode::Items,
+            1 => ForMode::Lines,
+            2 => ForMode::Words,
+            3 => ForMode::Split(String::decode(reader)?),
+            tag => return Err(invalid("FOR mode", tag)),
+        })
+    }
+}
+
+impl Encode for SourceLocation {
+    fn encode(&self, writer: &mut Writer) {
+        self.file.encode(writer);
+        self.line.encode(writer);
+        self.column.encode(writer);
+        self.text.encode(writer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(SourceLocation {
+            file: String::decode(reader)?,
+            line: usize::decode(reader)?,
+            column: usize::decode(reader)?,
+            text: String::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Op {
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            Op::Load(operand) => {
+                writer.u8(0);
+                operand.encode(writer);
+            }
+            Op::Store(name) => {
+                writer.u8(1);
+                name.encode(writer);
+            }
+            Op::Move(name, operand) => {
+                writer.u8(2);
+                name.encode(writer);
+                operand.encode(writer);
+            }
+            Op::Pop => writer.u8(3),
+            Op::Compare(comparison) => {
+                writer.u8(4);
+                comparison.encode(writer);
+            }
+            Op::Jump(target) => {
+                writer.u8(5);
+                target.encode(writer);
+            }
+            Op::JumpIfFalse(target) => {
+                writer.u8(6);
+                target.encode(writer);
+            }
+            Op::Items(mode, source) => {
+                writer.u8(7);
+                mode.encode(writer);
+                source.encode(writer);
+            }
+            Op::Next(var, target) => {
+                writer.u8(8);
+                var.encode(writer);
+                target.encode(writer);
+            }
+            Op::Try(target) => {
+                writer.u8(9);
+                target.encode(writer);
+            }
+            Op::EndTry => writer.u8(10),
+            Op::Catch(var, target) => {
+                writer.u8(11);
+                var.encode(writer);
+                target.encode(writer);
+            }
+            Op::Rethrow => writer.u8(12),
+            Op::Call(name, count) => {
+                writer.u8(13);
+                name.encode(writer);
+                count.encode(writer);
+            }
+            Op::Ret => writer.u8(14),
+            Op::LibCall(name) => {
+                writer.u8(15);
+                name.encode(writer);
+            }
+            Op::Defer(chunk) => {
+                writer.u8(16);
+                chunk.encode(writer);
+            }
+            Op::Global(names) => {
+                writer.u8(17);
+                names.encode(writer);
+            }
+            Op::Arithmetic(operator) => {
+                writer.u8(18);
+                operator.encode(writer);
+            }
+            Op::Print => writer.u8(19),
+            Op::Exec => writer.u8(20),
+            Op::Input(var) => {
+                writer.u8(21);
+                var.encode(writer);
+            }
+            Op::SetEnv(name) => {
+                writer.u8(22);
+                name.encode(writer);
+            }
+            Op::Abort(code) => {
+                writer.u8(23);
+                code.encode(writer);
+            }
+            Op::Not => writer.u8(24),
+            Op::Update(operator, target) => {
+                writer.u8(25);
+                operator.encode(writer);
+                target.encode(writer);
+            }
+            Op::RemoveEnv(name) => {
+                writer.u8(26);
+                name.encode(writer);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(match reader.u8()? {
+            0 => Op::Load(Operand::decode(reader)?),
+            1 => Op::Store(String::decode(reader)?),
+            2 => Op::Move(String::decode(reader)?, Operand::decode(reader)?),
+            3 => Op::Pop,
+            4 => Op::Compare(Comparison::decode(reader)?),
+            5 => Op::Jump(usize::decode(reader)?),
+            6 => Op::JumpIfFalse(usize::decode(reader)?),
+            7 => Op::Items(ForMode::decode(reader)?, String::decode(reader)?),
+            8 => Op::Next(String::decode(reader)?, usize::decode(reader)?),
+            9 => Op::Try(usize::decode(reader)?),
+            10 => Op::EndTry,
+            11 => Op::Catch(String::decode(reader)?, usize::decode(reader)?),
+            12 => Op::Rethrow,
+            13 => Op::Call(String::decode(reader)?, usize::decode(reader)?),
+            14 => Op::Ret,
+            15 => Op::LibCall(String::decode(reader)?),
+            16 => Op::Defer(Chunk::decode(reader)?),
+            17 => Op::Global(Vec::decode(reader)?),
+            18 => Op::Arithmetic(Operator::decode(reader)?),
+            19 => Op::Print,
+            20 => Op::Exec,
+            21 => Op::Input(String::decode(reader)?),
+            22 => Op::SetEnv(String::decode(reader)?),
+            23 => Op::Abort(i32::decode(reader)?),
+            24 => Op::Not,
+            25 => Op::Update(Operator::decode(reader)?, String::decode(reader)?),
+            26 => Op::RemoveEnv(String::decode(reader)?),
+            tag => return Err(invalid("instruction", tag)),
+        })
+    }
+}
+
+impl Encode for Chunk {
+    fn encode(&self, writer: &mut Writer) {
+        self.ops.encode(writer);
+        self.locations.encode(writer);
+        self.op_locations.encode(writer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        let chunk = Chunk {
+            ops: Vec::decode(reader)?,
+            locations: Vec::decode(reader)?,
+            op_locations: Vec::decode(reader)?,
+        };
+        if chunk.op_locations.len() != chunk.ops.len() {
+            return Err(CerealError::io("Compiled program has instructions without locations"));
+        }
+        Ok(chunk)
+    }
+}
+
+impl Encode for Function {
+    fn encode(&self, writer: &mut Writer) {
+        self.params.encode(writer);
+        self.code.encode(writer);
+        self.namespace.encode(writer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(Function {
+            params: Vec::decode(reader)?,
+            code: Chunk::decode(reader)?,
+            namespace: Option::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for CompiledProgram {
+    fn encode(&self, writer: &mut Writer) {
+        self.main.encode(writer);
+        self.functions.encode(writer);
+        self.globals.encode(writer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, CerealError> {
+        Ok(CompiledProgram {
+            main: Chunk::decode(reader)?,
+            functions: Vec::decode(reader)?,
+            globals: Vec::decode(reader)?,
+        })
+    }
+}
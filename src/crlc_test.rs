@@ -0,0 +1,74 @@
+use crate::crlc::{self, FORMAT_VERSION, MAGIC};
+use crate::vm::VM;
+use crate::value::Value;
+
+fn build(script: &str) -> Vec<u8> {
+    let mut vm = VM::new();
+    vm.load_source("build.cereal", script).unwrap();
+    crlc::encode(&vm.compiled_program())
+}
+
+#[test]
+fn test_compiled_program_runs_like_its_script() {
+    let script = r#"
+        DEF greeting "hello"
+        FN greet name DO
+            RETURN "$greeting, $name"
+        ENDFN
+        MOV names ["a", "b"]
+        MOV out ""
+        FOR name IN $names DO
+            CALL greet $name -> text
+            MOV out "$out$text;"
+        ENDFOR
+        TRY
+            MOV count 1.5
+            DIV count 0
+        CATCH err
+            MOV failed $err_kind
+        ENDTRY
+        DEFER MOV out "$out done"
+    "#;
+
+    let bytes = build(script);
+    assert!(crlc::is_compiled(&bytes));
+    assert_eq!(bytes, build(script));
+
+    let mut vm = VM::new();
+    vm.load_compiled(crlc::decode(&bytes).unwrap());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("out"), Some(&Value::from("hello, a;hello, b; done")));
+    assert_eq!(vm.get_variable("count"), Some(&Value::from(1.5)));
+    assert_eq!(vm.get_variable("failed"), Some(&Value::from("Runtime")));
+}
+
+#[test]
+fn test_compiled_program_keeps_source_locations() {
+    let bytes = build("MOV a 1\nFN fail DO\n    MOV b $missing\nENDFN\nCALL fail");
+
+    let mut vm = VM::new();
+    vm.load_compiled(crlc::decode(&bytes).unwrap());
+    let error = vm.execute().unwrap_err();
+    let location = error.location().unwrap();
+    assert_eq!((location.file.as_str(), location.line), ("build.cereal", 3));
+    assert_eq!(location.text, "    MOV b $missing");
+}
+
+#[test]
+fn test_damaged_compiled_programs_are_rejected() {
+    let bytes = build("MOV a 1\nPRINT $a");
+
+    assert!(crlc::decode(b"MOV a 1").is_err());
+    assert!(crlc::decode(MAGIC).is_err());
+    assert!(crlc::decode(&bytes[..bytes.len() - 1]).is_err());
+
+    let mut changed = bytes.clone();
+    *changed.last_mut().unwrap() ^= 1;
+    let error = crlc::decode(&changed).err().unwrap();
+    assert!(error.message().contains("checksum"), "{}", error);
+
+    let mut newer = bytes.clone();
+    newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    let error = crlc::decode(&newer).err().unwrap();
+    assert!(error.message().contains("version"), "{}", error);
+}
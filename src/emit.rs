@@ -0,0 +1,231 @@
+use crate::ast::{Statement, StatementKind};
+use crate::error::CerealError;
+use crate::lexer::{Lexer, TokenType};
+use crate::parser::Parser;
+use crate::source::SourceLocation;
+use crate::value;
+use crate::vm::VM;
+
+/// Stages of loading a script that `cereal --emit` can print
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Tokens,     // What the lexer reads, one token per line
+    Expanded,   // The script with macros expanded, e.g. `!httpget $website` as MOV and LIBCALL
+    Ast,        // The statement tree the parser builds
+    Bytecode,   // The compiled instructions of the script and each function
+}
+
+impl Stage {
+    pub fn from_name(name: &str) -> Option<Stage> {
+        match name {
+            "tokens" => Some(Stage::Tokens),
+            "expanded" => Some(Stage::Expanded),
+            "ast" => Some(Stage::Ast),
+            "bytecode" => Some(Stage::Bytecode),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the stages of loading a script up to the given one and formats what it produced.
+/// Errors are the same as loading the script would report.
+pub fn emit(stage: Stage, name: &str, source: &str) -> Result<String, CerealError> {
+    match stage {
+        Stage::Tokens => tokens(name, source),
+        Stage::Expanded => {
+            let mut output = String::new();
+            expanded(&parse(name, source)?, 0, &mut output);
+            Ok(output)
+        }
+        Stage::Ast => {
+            let mut output = String::new();
+            tree(&parse(name, source)?, 0, &mut output);
+            Ok(output)
+        }
+        Stage::Bytecode => bytecode(name, source),
+    }
+}
+
+fn parse(name: &str, source: &str) -> Result<Vec<Statement>, CerealError> {
+    let mut parser = Parser::new();
+    parser.set_source_name(name);
+    Ok(parser.parse_program(source)?.statements)
+}
+
+/// Lists each token with where it starts, its type and its text
+fn tokens(name: &str, source: &str) -> Result<String, CerealError> {
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize().map_err(|e| {
+        let span = lexer.error_span();
+        let text = source.lines().nth(span.line.saturating_sub(1)).unwrap_or_default();
+        e.with_location(SourceLocation::new(name, span, text))
+    })?;
+
+    let mut output = String::new();
+    for token in tokens {
+        let position = format!("{}:{}", token.span.line, token.span.column);
+        let text = match token.token_type {
+            TokenType::EOL => String::new(),
+            _ => token.to_source(),
+        };
+        output.push_str(format!("{:<8} {:<12} {}", position, format!("{:?}", token.token_type), text).trim_end());
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn indented(depth: usize, line: &str, output: &mut String) {
+    output.push_str(&"    ".repeat(depth));
+    output.push_str(line);
+    output.push('\n');
+}
+
+/// Writes statements back out as a script, as the VM sees them once macros have been expanded
+fn expanded(statements: &[Statement], depth: usize, output: &mut String) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Command(command) => {
+                for line in command.to_source().lines() {
+                    indented(depth, line, output);
+                }
+            }
+            StatementKind::If { branches, otherwise } => {
+                for (index, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if index == 0 { "IF" } else { "ELSEIF" };
+                    indented(depth, &format!("{} {}", keyword, condition.to_source()), output);
+                    expanded(body, depth + 1, output);
+                }
+                if let Some(body) = otherwise {
+                    indented(depth, "ELSE", output);
+                    expanded(body, depth + 1, output);
+                }
+                indented(depth, "ENDIF", output);
+            }
+            StatementKind::While { condition, body } => {
+                indented(depth, &format!("WHILE {}", condition.to_source()), output);
+                expanded(body, depth + 1, output);
+                indented(depth, "ENDWHILE", output);
+            }
+            StatementKind::For { var, source, mode, body } => {
+                let mode = mode.to_source().map(|mode| format!(" {}", mode)).unwrap_or_default();
+                indented(depth, &format!("FOR {} IN {}{} DO", var, value::quote(source), mode), output);
+                expanded(body, depth + 1, output);
+                indented(depth, "ENDFOR", output);
+            }
+            StatementKind::Try { body, catch, finally } => {
+                indented(depth, "TRY", output);
+                expanded(body, depth + 1, output);
+                if let Some(catch) = catch {
+                    indented(depth, &format!("CATCH {}", catch.var), output);
+                    expanded(&catch.body, depth + 1, output);
+                }
+                if let Some(body) = finally {
+                    indented(depth, "FINALLY", output);
+                    expanded(body, depth + 1, output);
+                }
+                indented(depth, "ENDTRY", output);
+            }
+            StatementKind::Break => indented(depth, "BREAK", output),
+            StatementKind::Continue => indented(depth, "CONTINUE", output),
+            StatementKind::Function { name, params, body } => {
+                let mut words = vec!["FN".to_string(), name.clone()];
+                words.extend(params.iter().cloned());
+                words.push("DO".to_string());
+                indented(depth, &words.join(" "), output);
+                expanded(body, depth + 1, output);
+                indented(depth, "ENDFN", output);
+            }
+            StatementKind::Import { path, namespace, .. } => {
+                let path = value::quote(path);
+                match namespace {
+                    Some(namespace) => indented(depth, &format!("IMPORT {} AS {}", path, namespace), output),
+                    None => indented(depth, &format!("IMPORT {}", path), output),
+                }
+            }
+        }
+    }
+}
+
+/// Writes the statement tree, one node per line with where its statement starts.
+/// Imported files are shown under their IMPORT.
+fn tree(statements: &[Statement], depth: usize, output: &mut String) {
+    for statement in statements {
+        let location = &statement.location;
+        let node = |label: String| format!("{}  @ {}:{}", label, location.line, location.column);
+
+        match &statement.kind {
+            StatementKind::Command(command) => {
+                // A library macro expands to several commands
+                indented(depth, &node(format!("Command {}", command.to_source().replace('\n', "; "))), output);
+            }
+            StatementKind::If { branches, otherwise } => {
+                indented(depth, &node("If".to_string()), output);
+                for (condition, body) in branches {
+                    indented(depth + 1, &format!("Branch {}", condition.to_source()), output);
+                    tree(body, depth + 2, output);
+                }
+                if let Some(body) = otherwise {
+                    indented(depth + 1, "Else", output);
+                    tree(body, depth + 2, output);
+                }
+            }
+            StatementKind::While { condition, body } => {
+                indented(depth, &node(format!("While {}", condition.to_source())), output);
+                tree(body, depth + 1, output);
+            }
+            StatementKind::For { var, source, mode, body } => {
+                let mode = mode.to_source().map(|mode| format!(" {}", mode)).unwrap_or_default();
+                indented(depth, &node(format!("For {} in {}{}", var, value::quote(source), mode)), output);
+                tree(body, depth + 1, output);
+            }
+            StatementKind::Try { body, catch, finally } => {
+                indented(depth, &node("Try".to_string()), output);
+                indented(depth + 1, "Body", output);
+                tree(body, depth + 2, output);
+                if let Some(catch) = catch {
+                    indented(depth + 1, &format!("Catch {}", catch.var), output);
+                    tree(&catch.body, depth + 2, output);
+                }
+                if let Some(body) = finally {
+                    indented(depth + 1, "Finally", output);
+                    tree(body, depth + 2, output);
+                }
+            }
+            StatementKind::Break => indented(depth, &node("Break".to_string()), output),
+            StatementKind::Continue => indented(depth, &node("Continue".to_string()), output),
+            StatementKind::Function { name, params, body } => {
+                indented(depth, &node(format!("Function {}({})", name, params.join(", "))), output);
+                tree(body, depth + 1, output);
+            }
+            StatementKind::Import { path, namespace, program } => {
+                let namespace = namespace.as_ref().map(|namespace| format!(" as {}", namespace)).unwrap_or_default();
+                indented(depth, &node(format!("Import {}{}", value::quote(path), namespace)), output);
+                tree(&program.statements, depth + 1, output);
+            }
+        }
+    }
+}
+
+/// Disassembles the compiled script, followed by each function and the variables loading it set,
+/// e.g. the DEFs of imported files
+fn bytecode(name: &str, source: &str) -> Result<String, CerealError> {
+    let mut vm = VM::quiet();
+    vm.load_source(name, source)?;
+    let program = vm.compiled_program();
+
+    let mut output = format!("== {} ==\n{}", name, program.main.disassemble());
+    for (name, function) in &program.functions {
+        let mut words = vec!["FN".to_string(), name.clone()];
+        words.extend(function.params.iter().cloned());
+        output.push_str(&format!("\n== {} ==\n{}", words.join(" "), function.code.disassemble()));
+    }
+
+    // Arguments and the environment are only set when the script runs
+    if !program.globals.is_empty() {
+        output.push_str("\n== globals ==\n");
+        for (name, value) in &program.globals {
+            output.push_str(&format!("{} = {}\n", name, value.to_literal()));
+        }
+    }
+    Ok(output)
+}
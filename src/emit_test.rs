@@ -0,0 +1,104 @@
+use crate::emit::{self, Stage};
+
+const SCRIPT: &str = r#"-- Constants Section
+DEF website "https://mkl.gg"
+FN search_website DO
+    !httpget $website
+    IF $http_get_body NOTCONTAINS $search_term
+        ABORT "Website does not contain $search_term"
+    ENDIF
+ENDFN
+CALL search_website
+"#;
+
+#[test]
+fn test_emit_tokens() {
+    let output = emit::emit(Stage::Tokens, "script.cereal", SCRIPT).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert_eq!(lines[0], "1:21     EOL");
+    assert_eq!(lines[1], "2:1      Command      DEF");
+    assert_eq!(lines[3], "2:13     String       \"https://mkl.gg\"");
+    assert!(lines.contains(&"4:5      Macro        !"));
+
+    let error = emit::emit(Stage::Tokens, "script.cereal", "PRINT \"open").unwrap_err();
+    assert_eq!(error.location().map(|location| location.line), Some(1));
+}
+
+#[test]
+fn test_emit_expanded() {
+    let output = emit::emit(Stage::Expanded, "script.cereal", SCRIPT).unwrap();
+    assert_eq!(output, r#"DEF website "https://mkl.gg"
+FN search_website DO
+    MOV r0 $website
+    LIBCALL httpget
+    IF $http_get_body NOTCONTAINS $search_term
+        ABORT "Website does not contain $search_term"
+    ENDIF
+ENDFN
+CALL search_website
+"#);
+
+    // The expanded form is a script in its own right
+    let script = r#"
+        MACRO twice value DO
+            ADD n $value
+            ADD n $value
+        ENDMACRO
+        MOV items ["a", "b c"]
+        FOR item IN $items DO
+            TRY
+                !twice 2
+            CATCH err
+                PRINT "failed: $err"
+            FINALLY
+                DEFER ENV DEPLOY_TARGET "prod eu"
+            ENDTRY
+            IF NOT ($item IS a OR $item IS "") AND $n GT 1.5
+                BREAK
+            ELSE
+                CALL report $item "x y" -> result
+            ENDIF
+        ENDFOR
+    "#;
+    let expanded = emit::emit(Stage::Expanded, "script.cereal", script).unwrap();
+    assert!(expanded.contains("    TRY\n        MOV value 2\n        ADD n $value\n        ADD n $value\n"), "{}", expanded);
+    assert_eq!(emit::emit(Stage::Expanded, "script.cereal", &expanded).unwrap(), expanded);
+}
+
+#[test]
+fn test_emit_ast() {
+    let output = emit::emit(Stage::Ast, "script.cereal", SCRIPT).unwrap();
+    assert_eq!(output, r#"Command DEF website "https://mkl.gg"  @ 2:1
+Function search_website()  @ 3:1
+    Command MOV r0 $website; LIBCALL httpget  @ 4:5
+    If  @ 5:5
+        Branch $http_get_body NOTCONTAINS $search_term
+            Command ABORT "Website does not contain $search_term"  @ 6:9
+Command CALL search_website  @ 9:1
+"#);
+}
+
+#[test]
+fn test_emit_bytecode() {
+    let output = emit::emit(Stage::Bytecode, "script.cereal", SCRIPT).unwrap();
+    assert_eq!(output, r#"== script.cereal ==
+0000    2  LOAD "https://mkl.gg"
+0001    |  STORE website
+0002    9  CALL search_website 0
+0003    |  POP
+
+== FN search_website ==
+0000    4  MOV r0 $website
+0001    |  LIBCALL httpget
+0002    5  LOAD $http_get_body
+0003    |  LOAD $search_term
+0004    |  NOTCONTAINS
+0005    |  JUMP_IF_FALSE 0009
+0006    6  LOAD "Website does not contain $search_term"
+0007    |  ABORT
+0008    5  JUMP 0009
+"#);
+
+    assert!(emit::emit(Stage::Bytecode, "script.cereal", "IF $a IS 1\n").is_err());
+}
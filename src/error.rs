@@ -0,0 +1,157 @@
+#![allow(dead_code)]
+
+use std::error::Error;
+use std::fmt;
+
+use crate::source::SourceLocation;
+
+/// Errors produced while lexing, parsing or running a script.
+/// Each error can carry the location of the code that caused it, which is
+/// filled in by the parser or VM once it is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CerealError {
+    // Invalid characters or literals in the source
+    Lex { message: String, location: Option<SourceLocation> },
+    // Commands with invalid arguments or unbalanced blocks
+    Parse { message: String, location: Option<SourceLocation> },
+    // A command name that isn't registered
+    UnknownCommand { name: String, location: Option<SourceLocation> },
+    // A LIBCALL or macro for a library that doesn't exist
+    UnknownLibrary { name: String, location: Option<SourceLocation> },
+    // Any other failure while running a command
+    Runtime { message: String, location: Option<SourceLocation> },
+    // Failure reading input, writing files or running processes
+    Io { message: String, location: Option<SourceLocation> },
+    // Failure making an HTTP request
+    Http { message: String, location: Option<SourceLocation> },
+    // The script stopped itself with ABORT
+    Aborted { message: String, code: i32, location: Option<SourceLocation> },
+}
+
+impl CerealError {
+    pub fn lex(message: impl Into<String>) -> Self {
+        CerealError::Lex { message: message.into(), location: None }
+    }
+
+    pub fn parse(message: impl Into<String>) -> Self {
+        CerealError::Parse { message: message.into(), location: None }
+    }
+
+    pub fn unknown_command(name: impl Into<String>) -> Self {
+        CerealError::UnknownCommand { name: name.into(), location: None }
+    }
+
+    pub fn unknown_library(name: impl Into<String>) -> Self {
+        CerealError::UnknownLibrary { name: name.into(), location: None }
+    }
+
+    pub fn runtime(message: impl Into<String>) -> Self {
+        CerealError::Runtime { message: message.into(), location: None }
+    }
+
+    pub fn io(message: impl Into<String>) -> Self {
+        CerealError::Io { message: message.into(), location: None }
+    }
+
+    pub fn http(message: impl Into<String>) -> Self {
+        CerealError::Http { message: message.into(), location: None }
+    }
+
+    pub fn aborted(message: impl Into<String>, code: i32) -> Self {
+        CerealError::Aborted { message: message.into(), code, location: None }
+    }
+
+    /// Short name of the kind of error, e.g. "Runtime"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CerealError::Lex { .. } => "Lex",
+            CerealError::Parse { .. } => "Parse",
+            CerealError::UnknownCommand { .. } => "UnknownCommand",
+            CerealError::UnknownLibrary { .. } => "UnknownLibrary",
+            CerealError::Runtime { .. } => "Runtime",
+            CerealError::Io { .. } => "Io",
+            CerealError::Http { .. } => "Http",
+            CerealError::Aborted { .. } => "Aborted",
+        }
+    }
+
+    /// The error message without its location
+    pub fn message(&self) -> String {
+        match self {
+            CerealError::UnknownCommand { name, .. } => format!("Unknown command: {}", name),
+            CerealError::UnknownLibrary { name, .. } => format!("Library '{}' not found", name),
+            CerealError::Lex { message, .. }
+            | CerealError::Parse { message, .. }
+            | CerealError::Runtime { message, .. }
+            | CerealError::Io { message, .. }
+            | CerealError::Http { message, .. }
+            | CerealError::Aborted { message, .. } => message.clone(),
+        }
+    }
+
+    pub fn location(&self) -> Option<&SourceLocation> {
+        match self {
+            CerealError::Lex { location, .. }
+            | CerealError::Parse { location, .. }
+            | CerealError::UnknownCommand { location, .. }
+            | CerealError::UnknownLibrary { location, .. }
+            | CerealError::Runtime { location, .. }
+            | CerealError::Io { location, .. }
+            | CerealError::Http { location, .. }
+            | CerealError::Aborted { location, .. } => location.as_ref(),
+        }
+    }
+
+    /// Attaches a location to the error, unless it already has one.
+    /// This keeps the innermost location, e.g. the failing line inside a function rather than its CALL.
+    pub fn with_location(mut self, new_location: SourceLocation) -> Self {
+        match &mut self {
+            CerealError::Lex { location, .. }
+            | CerealError::Parse { location, .. }
+            | CerealError::UnknownCommand { location, .. }
+            | CerealError::UnknownLibrary { location, .. }
+            | CerealError::Runtime { location, .. }
+            | CerealError::Io { location, .. }
+            | CerealError::Http { location, .. }
+            | CerealError::Aborted { location, .. } => {
+                location.get_or_insert(new_location);
+            }
+        }
+        self
+    }
+
+    /// Prefixes the message, e.g. with the name of the command that failed
+    pub fn context(self, prefix: &str) -> Self {
+        match self {
+            CerealError::Lex { message, location } => CerealError::Lex { message: format!("{}: {}", prefix, message), location },
+            CerealError::Parse { message, location } => CerealError::Parse { message: format!("{}: {}", prefix, message), location },
+            CerealError::Runtime { message, location } => CerealError::Runtime { message: format!("{}: {}", prefix, message), location },
+            CerealError::Io { message, location } => CerealError::Io { message: format!("{}: {}", prefix, message), location },
+            CerealError::Http { message, location } => CerealError::Http { message: format!("{}: {}", prefix, message), location },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for CerealError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location() {
+            Some(location) => write!(f, "{}", location.format_error(&self.message())),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl Error for CerealError {}
+
+impl From<std::io::Error> for CerealError {
+    fn from(error: std::io::Error) -> Self {
+        CerealError::io(error.to_string())
+    }
+}
+
+impl From<reqwest::Error> for CerealError {
+    fn from(error: reqwest::Error) -> Self {
+        CerealError::http(error.to_string())
+    }
+}
@@ -0,0 +1,232 @@
+use std::cmp::Ordering;
+use crate::lexer::{Token, TokenType};
+use crate::number::Number;
+use crate::error::CerealError;
+use crate::value::{self, Value};
+
+/// Operators that compare two values
+const COMPARISONS: &[&str] = &["IS", "NOT", "CONTAINS", "NOTCONTAINS", "GT", "LT", "GTE", "LTE"];
+
+/// How a condition compares its two sides.
+/// IS/NOT/CONTAINS/NOTCONTAINS compare text, GT/LT/GTE/LTE compare numbers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Is,
+    Not,
+    Contains,
+    NotContains,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+}
+
+impl Comparison {
+    pub fn from_name(name: &str) -> Option<Comparison> {
+        Some(match name {
+            "IS" => Comparison::Is,
+            "NOT" => Comparison::Not,
+            "CONTAINS" => Comparison::Contains,
+            "NOTCONTAINS" => Comparison::NotContains,
+            "GT" => Comparison::Gt,
+            "LT" => Comparison::Lt,
+            "GTE" => Comparison::Gte,
+            "LTE" => Comparison::Lte,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Comparison::Is => "IS",
+            Comparison::Not => "NOT",
+            Comparison::Contains => "CONTAINS",
+            Comparison::NotContains => "NOTCONTAINS",
+            Comparison::Gt => "GT",
+            Comparison::Lt => "LT",
+            Comparison::Gte => "GTE",
+            Comparison::Lte => "LTE",
+        }
+    }
+
+    /// Compares two values, the way they'd read once expanded into text
+    pub fn apply(self, left: &Value, right: &Value) -> Result<bool, CerealError> {
+        match self {
+            Comparison::Is => Ok(same_text(left, right)),
+            Comparison::Not => Ok(!same_text(left, right)),
+            Comparison::Contains => Ok(left.to_string().contains(&right.to_string())),
+            Comparison::NotContains => Ok(!left.to_string().contains(&right.to_string())),
+            Comparison::Gt | Comparison::Lt | Comparison::Gte | Comparison::Lte => {
+                let ordering = left.to_number()
+                    .and_then(|left| Ok(left.compare(right.to_number()?)))
+                    .map_err(|e| CerealError::runtime(e).context(self.name()))?;
+                Ok(match self {
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Gte => ordering != Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                })
+            }
+        }
+    }
+}
+
+/// Checks if two values have the same text, without formatting the common cases
+fn same_text(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::String(left), Value::String(right)) => left == right,
+        (Value::Number(Number::Int(left)), Value::Number(Number::Int(right))) => left == right,
+        _ => left.to_string() == right.to_string(),
+    }
+}
+
+/// A condition used by IF, ELSEIF and WHILE, built from tokens with the grammar:
+///
+/// ```text
+/// expression := and ("OR" and)*
+/// and        := unary ("AND" unary)*
+/// unary      := "NOT" unary | "(" expression ")" | comparison
+/// comparison := operand <operator> operand
+/// ```
+///
+/// Operands are kept as written and only expanded when the compiled condition runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Compare {
+        left: String,
+        operator: Comparison,
+        right: String,
+    },
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    /// Parses a full condition, returning an error if any tokens are left over
+    pub fn parse(tokens: &[Token]) -> Result<Expression, CerealError> {
+        if tokens.is_empty() {
+            return Err(CerealError::parse("Expected a condition"));
+        }
+
+        let mut parser = ExpressionParser { tokens, position: 0 };
+        let expression = parser.parse_or().map_err(CerealError::parse)?;
+
+        if let Some(token) = parser.peek() {
+            return Err(CerealError::parse(format!("Unexpected '{}' in condition", token.value)));
+        }
+        Ok(expression)
+    }
+
+    /// Formats the condition the way it would be written in a script,
+    /// with parentheses where AND and OR are nested
+    pub fn to_source(&self) -> String {
+        let grouped = |expression: &Expression| match expression {
+            Expression::And(..) | Expression::Or(..) => format!("({})", expression.to_source()),
+            _ => expression.to_source(),
+        };
+        match self {
+            Expression::Compare { left, operator, right } => {
+                format!("{} {} {}", value::quote(left), operator.name(), value::quote(right))
+            }
+            Expression::Not(inner) => format!("NOT {}", grouped(inner)),
+            Expression::And(left, right) => format!("{} AND {}", grouped(left), grouped(right)),
+            Expression::Or(left, right) => {
+                let side = |expression: &Expression| match expression {
+                    Expression::Or(..) => expression.to_source(),
+                    _ => grouped(expression),
+                };
+                format!("{} OR {}", side(left), side(right))
+            }
+        }
+    }
+}
+
+/// Recursive descent parser over the tokens of a single condition
+struct ExpressionParser<'t> {
+    tokens: &'t [Token],
+    position: usize,
+}
+
+impl<'t> ExpressionParser<'t> {
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'t Token> {
+        let token = self.peek()?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /// Checks if the next token is the given keyword (and not e.g. a string that happens to match)
+    fn at_keyword(&self, keyword: &str) -> bool {
+        self.peek().is_some_and(|token| token.token_type == TokenType::Identifier && token.value == keyword)
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_and()?;
+        while self.at_keyword("OR") {
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_unary()?;
+        while self.at_keyword("AND") {
+            self.next();
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        // NOT at the start of a term negates it; between two operands it is a comparison
+        if self.at_keyword("NOT") {
+            self.next();
+            return Ok(Expression::Not(Box::new(self.parse_unary()?)));
+        }
+
+        if self.peek().is_some_and(|token| token.token_type == TokenType::Symbol('(')) {
+            self.next();
+            let expression = self.parse_or()?;
+            return match self.next() {
+                Some(token) if token.token_type == TokenType::Symbol(')') => Ok(expression),
+                _ => Err("Missing ')' in condition".to_string()),
+            };
+        }
+
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression, String> {
+        let left = self.parse_operand()?;
+
+        let operator = match self.next() {
+            Some(token) if token.token_type == TokenType::Identifier && COMPARISONS.contains(&token.value.as_str()) => {
+                Comparison::from_name(&token.value).ok_or_else(|| format!("Unknown operator: {}", token.value))?
+            }
+            Some(token) => return Err(format!(
+                "Expected one of {} after '{}', found '{}'",
+                COMPARISONS.join("/"), left, token.value
+            )),
+            None => return Err(format!("Expected one of {} after '{}'", COMPARISONS.join("/"), left)),
+        };
+
+        let right = self.parse_operand()?;
+        Ok(Expression::Compare { left, operator, right })
+    }
+
+    fn parse_operand(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(token) if matches!(
+                token.token_type,
+                TokenType::String | TokenType::Variable | TokenType::Number | TokenType::Identifier
+            ) => Ok(token.value.clone()),
+            Some(token) => Err(format!("Expected a value in condition, found '{}'", token.value)),
+            None => Err("Expected a value at the end of the condition".to_string()),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::error::CerealError;
+use crate::libraries::LibraryExecutor;
+use crate::value::Value;
+
+/// Signature of the functions that transform a value in `${name | filter args...}`
+pub type FilterFn = fn(&Value, &[String]) -> Result<Value, CerealError>;
+
+/// Filters that can be used when interpolating, e.g. `${name | trim | upper}`.
+/// Libraries add their own filters in `LibraryExecutor::register_filters`.
+#[derive(Clone)]
+pub struct FilterRegistry {
+    filters: HashMap<String, FilterFn>,
+}
+
+impl FilterRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            filters: HashMap::new(),
+        };
+
+        // Register built-in filters
+        registry.register("upper", |value, args| {
+            expect_arguments("upper", args, 0)?;
+            Ok(Value::String(value.to_string().to_uppercase()))
+        });
+
+        registry.register("lower", |value, args| {
+            expect_arguments("lower", args, 0)?;
+            Ok(Value::String(value.to_string().to_lowercase()))
+        });
+
+        registry.register("trim", |value, args| {
+            expect_arguments("trim", args, 0)?;
+            Ok(Value::from(value.to_string().trim()))
+        });
+
+        // Number of items in a list or map, or characters in anything else
+        registry.register("length", |value, args| {
+            expect_arguments("length", args, 0)?;
+            let length = match value {
+                Value::List(items) => items.len(),
+                Value::Map(entries) => entries.len(),
+                _ => value.to_string().chars().count(),
+            };
+            Ok(Value::from(length as i64))
+        });
+
+        registry.register("replace", |value, args| {
+            expect_arguments("replace", args, 2)?;
+            Ok(Value::String(value.to_string().replace(&args[0], &args[1])))
+        });
+
+        LibraryExecutor::register_filters(&mut registry);
+
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, filter: FilterFn) {
+        self.filters.insert(name.to_lowercase(), filter);
+    }
+
+    /// Runs a filter call such as `replace "-" "_"`, after its arguments have been split by `parse_call`
+    pub fn apply(&self, name: &str, value: &Value, args: &[String]) -> Result<Value, CerealError> {
+        let filter = self.filters
+            .get(&name.to_lowercase())
+            .ok_or_else(|| CerealError::runtime(format!("Unknown filter: {}", name)))?;
+        filter(value, args)
+    }
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn expect_arguments(name: &str, args: &[String], count: usize) -> Result<(), CerealError> {
+    if args.len() != count {
+        return Err(CerealError::runtime(format!("Filter '{}' takes {} argument(s), got {}", name, count, args.len())));
+    }
+    Ok(())
+}
+
+/// Splits the inside of `${...}` at each `|` that isn't quoted or inside nested braces
+pub fn split_pipeline(text: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quote = None;
+
+    for (position, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => depth -= 1,
+            (None, '|') if depth == 0 => {
+                segments.push(&text[start..position]);
+                start = position + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&text[start..]);
+    segments
+}
+
+/// Splits a filter call into its name and arguments. Arguments are separated by whitespace
+/// and may be quoted with `"` or `'` to include spaces.
+pub fn parse_call(text: &str) -> Result<(&str, Vec<&str>), CerealError> {
+    let text = text.trim();
+    let name_end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let name = &text[..name_end];
+    if name.is_empty() {
+        return Err(CerealError::runtime("Expected a filter name after '|'"));
+    }
+
+    let mut args = Vec::new();
+    let mut rest = text[name_end..].trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (arg, length) = if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| CerealError::runtime(format!("Missing closing {} in filter '{}'", c, name)))?;
+            (&rest[1..end + 1], end + 2)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        args.push(arg);
+        rest = rest[length..].trim_start();
+    }
+    Ok((name, args))
+}
@@ -1,19 +1,46 @@
+use crate::source::Span;
+use crate::error::CerealError;
+
 /// A token representing a single lexical unit in the language
 #[derive(Debug, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub value: String,
+    pub span: Span,  // Where the token starts in the source
+}
+
+impl Token {
+    /// Formats the token the way it would be written in a script, e.g. with strings quoted
+    pub fn to_source(&self) -> String {
+        match self.token_type {
+            TokenType::String => {
+                let escaped: String = self.value.chars().map(|c| match c {
+                    '\n' => "\\n".to_string(),
+                    '\t' => "\\t".to_string(),
+                    '\r' => "\\r".to_string(),
+                    '"' => "\\\"".to_string(),
+                    '\\' => "\\\\".to_string(),
+                    c => c.to_string(),
+                }).collect();
+                format!("\"{}\"", escaped)
+            }
+            _ => self.value.clone(),
+        }
+    }
 }
 
 /// The different types of tokens that can be recognized
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TokenType {
     Command,     // Built-in commands like DEF, MOV, etc.
     Identifier,  // Names/identifiers
     String,      // String literals
+    Number,      // Integer and float literals
     Variable,    // Variables starting with $
     Macro,       // Macros starting with !
     Symbol(char),// Single characters like (, ), etc.
+    Arrow,       // The -> used to capture a function's return value
     EOL,         // End of line
 }
 
@@ -22,21 +49,34 @@ pub struct Lexer {
     position: usize,
     line: usize,
     column: usize,
+    token_start: Span,
 }
 
 impl Lexer {
     /// Creates a new Lexer instance for the given input string
     pub fn new(input: &str) -> Self {
+        Self::with_line(input, 1)
+    }
+
+    /// Creates a Lexer for input that starts at the given line of a larger source,
+    /// so token spans match the original file
+    pub fn with_line(input: &str, line: usize) -> Self {
         Lexer {
             input: input.chars().collect(),
             position: 0,
-            line: 1,
+            line,
             column: 1,
+            token_start: Span { line, column: 1 },
         }
     }
 
+    /// Returns where the token being read started, used to locate lexing errors
+    pub fn error_span(&self) -> Span {
+        self.token_start
+    }
+
     /// Tokenizes the entire input string into a vector of tokens
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, CerealError> {
         let mut tokens = Vec::new();
         
         while let Some(token) = self.next_token()? {
@@ -59,32 +99,31 @@ impl Lexer {
         Some(c)
     }
 
-    /// Skips over whitespace characters, updating line/column numbers
+    /// Skips over whitespace characters, stopping at the end of the line so it becomes an EOL token
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
-            if !c.is_whitespace() {
+            if !c.is_whitespace() || c == '\n' {
                 break;
             }
-            if c == '\n' {
-                self.line += 1;
-                self.column = 1;
-            }
             self.advance();
         }
     }
 
     /// Reads a string literal, handling escape sequences
-    fn read_string(&mut self) -> Result<Token, String> {
+    fn read_string(&mut self) -> Result<Token, CerealError> {
         let mut value = String::new();
         self.advance(); // Skip opening quote
 
         while let Some(c) = self.peek() {
             match c {
+                // Strings can't span lines
+                '\n' => break,
                 '"' => {
                     self.advance(); // Skip closing quote
                     return Ok(Token {
                         token_type: TokenType::String,
                         value,
+                        span: self.token_start,
                     });
                 }
                 '\\' => {
@@ -96,7 +135,7 @@ impl Lexer {
                             'r' => '\r',
                             '"' => '"',
                             '\\' => '\\',
-                            _ => return Err(format!("Invalid escape sequence: \\{}", next)),
+                            _ => return Err(CerealError::lex(format!("Invalid escape sequence: \\{}", next))),
                         });
                     }
                 }
@@ -106,7 +145,7 @@ impl Lexer {
                 }
             }
         }
-        Err("Unterminated string literal".to_string())
+        Err(CerealError::lex("Unterminated string literal"))
     }
 
     /// Reads an identifier or command token
@@ -114,7 +153,9 @@ impl Lexer {
         let mut value = String::new();
         
         while let Some(c) = self.peek() {
-            if !c.is_alphanumeric() && c != '_' {
+            // A dot joins namespaced names, e.g. CALL utils.deploy
+            let dotted = c == '.' && self.input.get(self.position + 1).is_some_and(|next| next.is_alphabetic() || *next == '_');
+            if !c.is_alphanumeric() && c != '_' && !dotted {
                 break;
             }
             value.push(c);
@@ -130,6 +171,7 @@ impl Lexer {
         Token {
             token_type,
             value,
+            span: self.token_start,
         }
     }
 
@@ -137,36 +179,130 @@ impl Lexer {
     fn is_command(&self, value: &str) -> bool {
         // Add all valid commands here
         let commands = [
-            "DEF", "MOV", "EXEC", "FN", 
-            "CALL", "ENDFN", "INPUT", "LIBCALL", "IF", "ENDIF",
-            "PRINT", "ABORT"
+            "DEF", "MOV", "GLOBAL", "EXEC", "FN", 
+            "CALL", "ENDFN", "INPUT", "LIBCALL", "IF", "ELSEIF", "ELSE", "ENDIF",
+            "WHILE", "ENDWHILE", "RETURN", "PRINT", "ABORT",
+            "ADD", "SUB", "MUL", "DIV", "MOD",
+            "TRY", "CATCH", "FINALLY", "ENDTRY", "DEFER",
+            "FOR", "ENDFOR", "BREAK", "CONTINUE", "ENV", "IMPORT", "MACRO", "ENDMACRO"
         ];
         commands.contains(&value)
     }
 
-    /// Reads a variable token (starting with $)
-    fn read_variable(&mut self) -> Token {
-        self.advance(); // Skip $
+    /// Reads an integer or float literal, with an optional leading minus sign and exponent
+    fn read_number(&mut self) -> Result<Token, CerealError> {
         let mut value = String::new();
-        
+
+        if self.peek() == Some('-') {
+            value.push('-');
+            self.advance();
+        }
+
         while let Some(c) = self.peek() {
-            if !c.is_alphanumeric() && c != '_' {
+            let is_decimal_point = c == '.'
+                && !value.contains('.')
+                && self.input.get(self.position + 1).is_some_and(|next| next.is_ascii_digit());
+            if !c.is_ascii_digit() && !is_decimal_point {
                 break;
             }
             value.push(c);
             self.advance();
         }
 
+        // An exponent, e.g. 1.5e308 or 2E-3
+        if let Some(e @ ('e' | 'E')) = self.peek() {
+            value.push(e);
+            self.advance();
+            if let Some(sign @ ('+' | '-')) = self.peek() {
+                value.push(sign);
+                self.advance();
+            }
+            while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+                value.push(c);
+                self.advance();
+            }
+        }
+
+        // A number running straight into a name (e.g. 12abc or 1e) would otherwise split into two tokens
+        let length = value.len();
+        while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+            value.push(c);
+            self.advance();
+        }
+        if value.len() > length || !value.ends_with(|c: char| c.is_ascii_digit()) {
+            return Err(CerealError::lex(format!("Invalid number: {}", value)));
+        }
+
+        Ok(Token {
+            token_type: TokenType::Number,
+            value,
+            span: self.token_start,
+        })
+    }
+
+    /// Reads a variable token (starting with $)
+    fn read_variable(&mut self) -> Token {
+        self.advance(); // Skip $
+        let mut value = String::new();
+
+        match self.peek() {
+            // An escaped $, e.g. $$5
+            Some('$') => {
+                self.advance();
+                value.push('$');
+            }
+            // A braced reference, e.g. ${name}, taken as it is up to the matching brace
+            Some('{') => {
+                let mut depth = 0;
+                while let Some(c) = self.peek().filter(|c| *c != '\n') {
+                    self.advance();
+                    value.push(c);
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = self.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        value.push(c);
+                        self.advance();
+                    } else if c == '.' && self.input.get(self.position + 1).is_some_and(|next| next.is_alphabetic() || *next == '_') {
+                        // Field access, e.g. $cfg.port
+                        value.push(c);
+                        self.advance();
+                    } else if c == '[' && self.input[self.position..].iter().take_while(|c| !c.is_whitespace()).any(|c| *c == ']') {
+                        // Index, e.g. $hosts[0] or $hosts[$i]
+                        while let Some(c) = self.advance() {
+                            value.push(c);
+                            if c == ']' {
+                                break;
+                            }
+                        }
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+
         Token {
             token_type: TokenType::Variable,
             value: "$".to_string() + &value,
+            span: self.token_start,
         }
     }
 
     /// Returns the next token from the input
     /// Handles comments, strings, variables, macros, and other tokens
-    fn next_token(&mut self) -> Result<Option<Token>, String> {
+    fn next_token(&mut self) -> Result<Option<Token>, CerealError> {
         self.skip_whitespace();
+        self.token_start = Span { line: self.line, column: self.column };
 
         let c = match self.peek() {
             None => return Ok(None),
@@ -190,6 +326,17 @@ impl Lexer {
                 }
                 return self.next_token();
             }
+            '-' if self.input.get(self.position + 1) == Some(&'>') => {
+                self.advance();
+                self.advance();
+                Token {
+                    token_type: TokenType::Arrow,
+                    value: "->".to_string(),
+                    span: self.token_start,
+                }
+            }
+            '-' if self.input.get(self.position + 1).is_some_and(|next| next.is_ascii_digit()) => self.read_number()?,
+            c if c.is_ascii_digit() => self.read_number()?,
             '"' => self.read_string()?,
             '$' => self.read_variable(),
             '!' => {
@@ -197,6 +344,7 @@ impl Lexer {
                 Token {
                     token_type: TokenType::Macro,
                     value: "!".to_string(),
+                    span: self.token_start,
                 }
             }
             c if c.is_alphabetic() => self.read_identifier_or_command(),
@@ -207,6 +355,7 @@ impl Lexer {
                 Token {
                     token_type: TokenType::EOL,
                     value: "\n".to_string(),
+                    span: self.token_start,
                 }
             }
             c => {
@@ -214,6 +363,7 @@ impl Lexer {
                 Token {
                     token_type: TokenType::Symbol(c),
                     value: c.to_string(),
+                    span: self.token_start,
                 }
             }
         };
@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
     use crate::lexer::{Lexer, TokenType};
+    use crate::source::Span;
 
     fn assert_tokens(input: &str, expected: Vec<(TokenType, &str)>) {
         let mut lexer = Lexer::new(input);
@@ -21,7 +22,29 @@ mod tests {
     fn test_empty_input() {
         assert_tokens("", vec![]);
         assert_tokens("    ", vec![]);
-        assert_tokens("\n\t\r", vec![]);
+        assert_tokens("\t\r", vec![]);
+    }
+
+    #[test]
+    fn test_end_of_line() {
+        assert_tokens("\n\t\r", vec![(TokenType::EOL, "\n")]);
+        assert_tokens(
+            "MOV x 1 // set x\nPRINT $x",
+            vec![
+                (TokenType::Command, "MOV"),
+                (TokenType::Identifier, "x"),
+                (TokenType::Number, "1"),
+                (TokenType::EOL, "\n"),
+                (TokenType::Command, "PRINT"),
+                (TokenType::Variable, "$x")
+            ]
+        );
+
+        // Strings and braced references end at the end of their line
+        let mut lexer = Lexer::new("PRINT \"open\nPRINT \"closed\"");
+        assert!(lexer.tokenize().is_err());
+        assert_eq!(lexer.error_span(), Span { line: 1, column: 7 });
+        assert_tokens("${name\nx", vec![(TokenType::Variable, "${name"), (TokenType::EOL, "\n"), (TokenType::Identifier, "x")]);
     }
 
     #[test]
@@ -29,6 +52,8 @@ mod tests {
         assert_tokens("DEF", vec![(TokenType::Command, "DEF")]);
         assert_tokens("MOV", vec![(TokenType::Command, "MOV")]);
         assert_tokens("EXEC", vec![(TokenType::Command, "EXEC")]);
+        assert_tokens("ELSEIF", vec![(TokenType::Command, "ELSEIF")]);
+        assert_tokens("ELSE", vec![(TokenType::Command, "ELSE")]);
     }
 
     #[test]
@@ -36,6 +61,12 @@ mod tests {
         assert_tokens("x", vec![(TokenType::Identifier, "x")]);
         assert_tokens("variable_name", vec![(TokenType::Identifier, "variable_name")]);
         assert_tokens("abc123", vec![(TokenType::Identifier, "abc123")]);
+        assert_tokens("utils.deploy", vec![(TokenType::Identifier, "utils.deploy")]);
+        assert_tokens("x. y", vec![
+            (TokenType::Identifier, "x"),
+            (TokenType::Symbol('.'), "."),
+            (TokenType::Identifier, "y"),
+        ]);
     }
 
     #[test]
@@ -50,6 +81,23 @@ mod tests {
         assert_tokens("$x", vec![(TokenType::Variable, "$x")]);
         assert_tokens("$var_name", vec![(TokenType::Variable, "$var_name")]);
         assert_tokens("$abc123", vec![(TokenType::Variable, "$abc123")]);
+        assert_tokens("$hosts[0]", vec![(TokenType::Variable, "$hosts[0]")]);
+        assert_tokens("$cfg.servers[$i].name", vec![(TokenType::Variable, "$cfg.servers[$i].name")]);
+        assert_tokens("$x. $y[", vec![
+            (TokenType::Variable, "$x"),
+            (TokenType::Symbol('.'), "."),
+            (TokenType::Variable, "$y"),
+            (TokenType::Symbol('['), "["),
+        ]);
+        assert_tokens("${name}x", vec![
+            (TokenType::Variable, "${name}"),
+            (TokenType::Identifier, "x"),
+        ]);
+        assert_tokens("${cfg.hosts[0]}", vec![(TokenType::Variable, "${cfg.hosts[0]}")]);
+        assert_tokens("$$5", vec![
+            (TokenType::Variable, "$$"),
+            (TokenType::Number, "5"),
+        ]);
     }
 
     #[test]
@@ -60,6 +108,37 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_numbers() {
+        assert_tokens("42", vec![(TokenType::Number, "42")]);
+        assert_tokens("3.25", vec![(TokenType::Number, "3.25")]);
+        assert_tokens("-7", vec![(TokenType::Number, "-7")]);
+        assert_tokens("1.5e308", vec![(TokenType::Number, "1.5e308")]);
+        assert_tokens("2E-3", vec![(TokenType::Number, "2E-3")]);
+        assert_tokens("ADD count 1", vec![
+            (TokenType::Command, "ADD"),
+            (TokenType::Identifier, "count"),
+            (TokenType::Number, "1")
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_numbers() {
+        assert_eq!(Lexer::new("1.5e").tokenize().unwrap_err().to_string(), "Invalid number: 1.5e");
+        assert_eq!(Lexer::new("MOV a 1e+").tokenize().unwrap_err().to_string(), "Invalid number: 1e+");
+        assert_eq!(Lexer::new("MOV a 12abc").tokenize().unwrap_err().to_string(), "Invalid number: 12abc");
+    }
+
+    #[test]
+    fn test_arrow() {
+        assert_tokens("CALL f -> result", vec![
+            (TokenType::Command, "CALL"),
+            (TokenType::Identifier, "f"),
+            (TokenType::Arrow, "->"),
+            (TokenType::Identifier, "result")
+        ]);
+    }
+
     #[test]
     fn test_comments() {
         assert_tokens("// This is a comment", vec![]);
@@ -82,6 +161,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_token_spans() {
+        let mut lexer = Lexer::with_line("  MOV x \"a b\"\nPRINT $x", 7);
+        let spans: Vec<Span> = lexer.tokenize().unwrap().iter().map(|t| t.span).collect();
+
+        assert_eq!(spans, vec![
+            Span { line: 7, column: 3 },
+            Span { line: 7, column: 7 },
+            Span { line: 7, column: 9 },
+            Span { line: 7, column: 14 },
+            Span { line: 8, column: 1 },
+            Span { line: 8, column: 7 },
+        ]);
+    }
+
+    #[test]
+    fn test_error_span() {
+        let mut lexer = Lexer::new("PRINT \"Unterminated");
+        assert!(lexer.tokenize().is_err());
+        assert_eq!(lexer.error_span(), Span { line: 1, column: 7 });
+    }
+
     #[test]
     fn test_invalid_string() {
         let mut lexer = Lexer::new("\"Unterminated string");
@@ -1,11 +1,22 @@
 #[allow(dead_code)]
 pub mod parser;
+pub mod ast;
+pub mod bytecode;
+pub mod compiler;
+pub mod crlc;
+pub mod emit;
 pub mod lexer;
 pub mod command;
 pub mod commands;
 pub mod libraries;
 pub mod vm;
 pub mod consts;
+pub mod number;
+pub mod expression;
+pub mod source;
+pub mod error;
+pub mod value;
+pub mod filters;
 
 #[cfg(test)]
 mod parser_test;
@@ -15,3 +26,9 @@ mod vm_test;
 
 #[cfg(test)]
 mod lexer_test; 
+
+#[cfg(test)]
+mod crlc_test;
+
+#[cfg(test)]
+mod emit_test;
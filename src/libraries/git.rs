@@ -1,6 +1,6 @@
 use std::process::Command;
 
-use crate::{command::ExecutionContext, consts::Registers};
+use crate::{command::ExecutionContext, consts::Registers, error::CerealError};
 
 
 pub struct Git {}
@@ -11,17 +11,24 @@ impl Git {
     }
 }
 
+impl Default for Git {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Git {
-    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        let command = context.variables.get(Registers::R0).unwrap();
-        let default_args = String::new();
+    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), CerealError> {
+        let command = context.get_variable(Registers::R0)
+            .ok_or_else(|| CerealError::runtime("git: r0 must be set to the git command"))?
+            .to_string();
 
         // Get the git command from r0 and arguments from r1
-        let args = context.variables.get(Registers::R1).unwrap_or(&default_args);
+        let args = context.get_variable(Registers::R1).map(|args| args.to_string()).unwrap_or_default();
 
         // Ensure both command and args are not empty
         if command.is_empty() {
-            return Err("Git command or arguments cannot be empty".to_string());
+            return Err(CerealError::runtime("Git command or arguments cannot be empty"));
         }
 
         // Execute git command using process
@@ -29,16 +36,16 @@ impl Git {
             Command::new("cmd")
                 .arg("/C")
                 .arg("git")
-                .arg(command)
-                .arg(args)
+                .arg(&command)
+                .arg(&args)
                 .output()
-                .map_err(|e| format!("Failed to execute git command: {}", e))?
+                .map_err(|e| CerealError::io(format!("Failed to execute git command: {}", e)))?
         } else {
             Command::new("git")
-                .arg(command)
-                .arg(args) 
+                .arg(&command)
+                .arg(&args)
                 .output()
-                .map_err(|e| format!("Failed to execute git command: {}", e))?
+                .map_err(|e| CerealError::io(format!("Failed to execute git command: {}", e)))?
         };
 
         // Convert output to string and store in context
@@ -54,7 +61,7 @@ impl Git {
         }
         
         if !output.status.success() {
-            return Err(format!("Git command failed with exit code: {}", output.status));
+            return Err(CerealError::runtime(format!("Git command failed with exit code: {}", output.status)));
         }
 
         Ok(())
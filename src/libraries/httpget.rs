@@ -1,4 +1,7 @@
 use crate::command::ExecutionContext;
+use crate::error::CerealError;
+use crate::filters::FilterRegistry;
+use crate::value::Value;
 
 
 pub struct HttpGet {}
@@ -9,21 +12,43 @@ impl HttpGet {
     }
 }
 
+impl Default for HttpGet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpGet {
-    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        let url = context.variables.get("r0").unwrap();
+    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), CerealError> {
+        let url = context.get_variable("r0")
+            .ok_or_else(|| CerealError::runtime("httpget: r0 must be set to the URL"))?
+            .to_string();
 
         // Ensure both command and args are not empty
         if url.is_empty() {
-            return Err("Git command or arguments cannot be empty".to_string());
+            return Err(CerealError::runtime("URL cannot be empty"));
         }
 
-        let output = reqwest::blocking::get(url).unwrap();
-        let body = output.text().unwrap();
+        let output = reqwest::blocking::get(&url)?;
+        let body = output.text()?;
 
         context.set_variable("http_get_body".to_string(), body);
 
         Ok(())
     }
+
+    pub fn register_filters(registry: &mut FilterRegistry) {
+        // Percent-encodes everything except unreserved characters, for use in query strings
+        registry.register("urlencode", |value, args| {
+            if !args.is_empty() {
+                return Err(CerealError::runtime("Filter 'urlencode' takes no arguments"));
+            }
+            let encoded = value.to_string().bytes().map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            }).collect::<String>();
+            Ok(Value::String(encoded))
+        });
+    }
 }
 
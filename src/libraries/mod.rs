@@ -1,4 +1,6 @@
 use crate::command::ExecutionContext;
+use crate::error::CerealError;
+use crate::filters::FilterRegistry;
 pub mod git;
 pub mod httpget;
 pub mod writef;
@@ -9,13 +11,24 @@ impl LibraryExecutor {
         LibraryExecutor {}
     }
 
-    pub fn execute(&self, name: &str, context: &mut ExecutionContext) -> Result<(), String> {
+    pub fn execute(&self, name: &str, context: &mut ExecutionContext) -> Result<(), CerealError> {
         match name {
             "git" => git::Git::new().execute(context),
             "httpget" => httpget::HttpGet::new().execute(context),
             "writef" => writef::WriteF::new().execute(context),
-            _ => Err(format!("Library '{}' not found", name)),
+            _ => Err(CerealError::unknown_library(name)),
         }
     }
+
+    /// Lets libraries add the filters they provide for interpolation, e.g. `${url | urlencode}`
+    pub fn register_filters(registry: &mut FilterRegistry) {
+        httpget::HttpGet::register_filters(registry);
+    }
+}
+
+impl Default for LibraryExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
@@ -3,6 +3,7 @@ use std::io::Write;
 
 use crate::command::ExecutionContext;
 use crate::consts::Registers;
+use crate::error::CerealError;
 
 
 pub struct WriteF {}
@@ -13,23 +14,32 @@ impl WriteF {
     }
 }
 
+impl Default for WriteF {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WriteF {
-    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), String> {
-        let filename = context.variables.get(Registers::R0).unwrap();
-        let data = context.variables.get(Registers::R1).unwrap();
+    pub fn execute(&self, context: &mut ExecutionContext) -> Result<(), CerealError> {
+        let filename = context.get_variable(Registers::R0)
+            .ok_or_else(|| CerealError::runtime("writef: r0 must be set to the file name"))?
+            .to_string();
+        let data = context.get_variable(Registers::R1)
+            .ok_or_else(|| CerealError::runtime("writef: r1 must be set to the data to write"))?
+            .to_string();
 
         if filename.is_empty() || data.is_empty() {
-            return Err("Filename or data cannot be empty".to_string());
+            return Err(CerealError::runtime("Filename or data cannot be empty"));
         }
 
-        if let Ok(mut file) = OpenOptions::new()
+        let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(filename) 
-        {
-            writeln!(file, "{}", data).unwrap();
-        }
+            .open(&filename)
+            .map_err(|e| CerealError::io(format!("Failed to open {}: {}", filename, e)))?;
+        writeln!(file, "{}", data)?;
 
         Ok(())
     }
@@ -2,14 +2,27 @@
 mod command;
 mod commands;
 mod parser;
+mod ast;
+mod bytecode;
+mod compiler;
+mod crlc;
+mod emit;
 mod vm;
 use vm::VM;
+use error::CerealError;
 mod libraries;
 mod consts;
+mod number;
+mod expression;
+mod source;
+mod error;
+mod value;
+mod filters;
 mod lexer;
 
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::io::{self, Write};
 
@@ -34,21 +47,15 @@ fn run_repl() {
                         continue;
                     }
                     
-                    // Execute accumulated buffer
-                    if let Err(e) = vm.load_string(&buffer) {
-                        eprintln!("Error: {}", e);
-                        buffer.clear();
-                        continue;
-                    }
-                    
-                    if let Err(e) = vm.execute() {
-                        eprintln!("Error: {}", e);
-                        buffer.clear();
-                        continue;
+                    // Execute accumulated buffer. An ABORT only stops this batch, not the REPL
+                    let result = vm.load_string(&buffer).and_then(|_| vm.execute());
+                    if let Err(e) = result {
+                        report_error("Error", &e);
                     }
-                    
-                    // Clear buffer after successful execution
+
+                    // Clear the buffer and the loaded commands, so the next RUN starts fresh
                     buffer.clear();
+                    vm.clear_commands();
                 } else if input.trim() == "EXIT" {
                     break;
                 } else if input.starts_with("LOAD") {
@@ -81,11 +88,20 @@ fn main() {
         return;
     }
     
-    // ... existing code for script file execution ...
+    if args[1] == "build" {
+        build(&args[2..]);
+        return;
+    }
+
+    if args[1] == "--emit" {
+        emit(&args[2..]);
+        return;
+    }
+
     let script_path = args[1].clone();
 
-    // Read the script file
-    let script_content = match fs::read_to_string(&script_path) {
+    // Read the script file, which may be a script or a program compiled with `cereal build`
+    let content = match fs::read(&script_path) {
         Ok(content) => content,
         Err(e) => {
             eprintln!("Error reading script file '{}': {}", script_path, e);
@@ -95,16 +111,110 @@ fn main() {
 
     // Create a new Virtual Machine instance
     let mut vm = VM::new();
-    
+    if let Err(e) = vm.set_script_args(&args[2..]) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
     // Load and execute the script
-    if let Err(e) = vm.load_string(&script_content) {
+    let loaded = if crlc::is_compiled(&content) {
+        crlc::decode(&content).map(|program| vm.load_compiled(program))
+    } else {
+        match String::from_utf8(content) {
+            Ok(script_content) => vm.load_source(&script_path, &script_content),
+            Err(_) => Err(CerealError::io(format!("'{}' is not a UTF-8 text file", script_path))),
+        }
+    };
+    if let Err(e) = loaded {
         eprintln!("Error loading script: {}", e);
         process::exit(1);
     }
 
     // Execute all commands in the VM
     if let Err(e) = vm.execute() {
-        eprintln!("Error executing program: {}", e);
+        report_error("Error executing program", &e);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// `cereal build <script> [-o <output>]` compiles a script and writes it to a file that runs
+/// without being parsed again. The output defaults to the script's path ending in `.crlc`.
+fn build(args: &[String]) {
+    let (script_path, output) = match args {
+        [script] => (script, Path::new(script).with_extension("crlc")),
+        [script, flag, output] if flag == "-o" => (script, PathBuf::from(output)),
+        _ => {
+            eprintln!("Usage: cereal build <script> [-o <output>]");
+            process::exit(1);
+        }
+    };
+
+    let script_content = match fs::read_to_string(script_path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Error reading script file '{}': {}", script_path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut vm = VM::new();
+    if let Err(e) = vm.load_source(script_path, &script_content) {
+        eprintln!("Error loading script: {}", e);
         process::exit(1);
     }
+
+    if let Err(e) = fs::write(&output, crlc::encode(&vm.compiled_program())) {
+        eprintln!("Error writing '{}': {}", output.display(), e);
+        process::exit(1);
+    }
+    println!("Built '{}' from '{}'", output.display(), script_path);
+}
+
+/// `cereal --emit tokens|expanded|ast|bytecode <script>` prints what a stage of loading the script produces
+fn emit(args: &[String]) {
+    let (stage, script_path) = match args {
+        [stage, script] => match emit::Stage::from_name(stage) {
+            Some(stage) => (stage, script),
+            None => {
+                eprintln!("Unknown stage '{}', expected tokens, expanded, ast or bytecode", stage);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("Usage: cereal --emit tokens|expanded|ast|bytecode <script>");
+            process::exit(1);
+        }
+    };
+
+    let script_content = match fs::read_to_string(script_path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Error reading script file '{}': {}", script_path, e);
+            process::exit(1);
+        }
+    };
+
+    match emit::emit(stage, script_path, &script_content) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("Error loading script: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints an error, showing ABORT messages as the script wrote them
+fn report_error(prefix: &str, error: &CerealError) {
+    match error {
+        CerealError::Aborted { message, .. } => eprintln!("ABORT: {}", message),
+        _ => eprintln!("{}: {}", prefix, error),
+    }
+}
+
+/// Exit status for a script that failed: the code given to ABORT, otherwise 1
+fn exit_code(error: &CerealError) -> i32 {
+    match error {
+        CerealError::Aborted { code, .. } => *code,
+        _ => 1,
+    }
 }
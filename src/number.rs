@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A numeric value. Numbers written as text are parsed when a command needs them,
+/// and arithmetic results are stored as numbers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Parses an integer or float, returning an error naming the value if it isn't numeric
+    pub fn parse(value: &str) -> Result<Number, String> {
+        let value = value.trim();
+        if let Ok(int) = value.parse::<i64>() {
+            return Ok(Number::Int(int));
+        }
+        match value.parse::<f64>() {
+            Ok(float) if float.is_finite() => Ok(Number::Float(float)),
+            _ => Err(format!("'{}' is not a number", value)),
+        }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(int) => int as f64,
+            Number::Float(float) => float,
+        }
+    }
+
+    pub fn checked_add(self, other: Number) -> Result<Number, String> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.checked_add(b).map(Number::Int).ok_or_else(overflow),
+            (a, b) => float(a.as_f64() + b.as_f64()),
+        }
+    }
+
+    pub fn checked_sub(self, other: Number) -> Result<Number, String> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.checked_sub(b).map(Number::Int).ok_or_else(overflow),
+            (a, b) => float(a.as_f64() - b.as_f64()),
+        }
+    }
+
+    pub fn checked_mul(self, other: Number) -> Result<Number, String> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.checked_mul(b).map(Number::Int).ok_or_else(overflow),
+            (a, b) => float(a.as_f64() * b.as_f64()),
+        }
+    }
+
+    /// Divides, truncating when both values are integers
+    pub fn checked_div(self, other: Number) -> Result<Number, String> {
+        if other.as_f64() == 0.0 {
+            return Err("Division by zero".to_string());
+        }
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.checked_div(b).map(Number::Int).ok_or_else(overflow),
+            (a, b) => float(a.as_f64() / b.as_f64()),
+        }
+    }
+
+    /// Remainder of the division, with the sign of the left value
+    pub fn checked_rem(self, other: Number) -> Result<Number, String> {
+        if other.as_f64() == 0.0 {
+            return Err("Division by zero".to_string());
+        }
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.checked_rem(b).map(Number::Int).ok_or_else(overflow),
+            (a, b) => float(a.as_f64() % b.as_f64()),
+        }
+    }
+
+    pub fn compare(self, other: Number) -> Ordering {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(&b),
+            (a, b) => a.as_f64().total_cmp(&b.as_f64()),
+        }
+    }
+}
+
+fn overflow() -> String {
+    "Integer overflow".to_string()
+}
+
+/// Float results that are too large to store are an error, like integer overflow
+fn float(value: f64) -> Result<Number, String> {
+    if value.is_finite() {
+        Ok(Number::Float(value))
+    } else {
+        Err("Float overflow".to_string())
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(int) => write!(f, "{}", int),
+            Number::Float(float) => write!(f, "{}", float),
+        }
+    }
+}
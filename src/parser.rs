@@ -1,15 +1,49 @@
 use crate::commands::registry::CommandRegistry;
 use crate::commands::*;
 use crate::command::{Command, MultiCommand};
+use crate::ast::{Catch, ForMode, Program, Statement, StatementKind};
+use crate::expression::Expression;
 use crate::lexer::{Lexer, Token, TokenType};
-use std::fs::OpenOptions;
-use std::io::Write;
+use crate::source::{SourceLocation, Span};
+use crate::error::CerealError;
+use crate::value::Operand;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::vec::IntoIter;
+
+/// Keywords that open, continue or close a block, or define something. They span several lines,
+/// so they are parsed by `parse_program` rather than the command registry.
+const BLOCK_KEYWORDS: &[&str] = &[
+    "IF", "ELSEIF", "ELSE", "ENDIF", "WHILE", "ENDWHILE", "FOR", "ENDFOR", "BREAK", "CONTINUE",
+    "TRY", "CATCH", "FINALLY", "ENDTRY", "FN", "ENDFN", "MACRO", "ENDMACRO", "IMPORT",
+];
+
+/// Keywords that continue or close a block, paired with the keyword that opens it
+const CLOSING_KEYWORDS: &[(&str, &str)] = &[
+    ("ELSEIF", "IF"), ("ELSE", "IF"), ("ENDIF", "IF"), ("ENDWHILE", "WHILE"), ("ENDFOR", "FOR"),
+    ("CATCH", "TRY"), ("FINALLY", "TRY"), ("ENDTRY", "TRY"), ("ENDFN", "FN"), ("ENDMACRO", "MACRO"),
+];
+
+/// A user-defined macro: the names of its parameters and the statements it expands to
+#[derive(Clone)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<Statement>,
+}
 
 #[derive(Clone)]
 pub struct Parser {
     current_line: usize,
     registry: CommandRegistry,
     last_args: Vec<String>,
+    source_name: String,  // File name used when reporting errors
+    // User-defined macros, expanded wherever they are called
+    macros: HashMap<String, Macro>,
+    // Macro whose body is being parsed, which can't call itself
+    defining: Option<String>,
+    // Files currently being imported, to detect import cycles
+    import_stack: Vec<PathBuf>,
 }
 
 impl Parser {
@@ -17,64 +51,66 @@ impl Parser {
         let mut registry = CommandRegistry::new();
         
         // Register the built-in commands
-        registry.register("DEF", "DEF", |args| {
+        registry.register_with_tokens("DEF", "DEF", |args| {
             if args.len() < 2 {
-                return Err("DEF requires variable name and value".to_string());
+                return Err(CerealError::parse("DEF requires variable name and value"));
             }
             Ok(Box::new(DefCommand::new(
-                args[0].to_string(),
-                args[1..].join(" "),
+                args[0].value.clone(),
+                Operand::from_tokens(&args[1..])?,
             )))
         });
 
-        registry.register("MOV", "MOV", |args| {
+        registry.register_with_tokens("MOV", "MOV", |args| {
             if args.len() < 2 {
-                return Err("MOV requires two arguments".to_string());
+                return Err(CerealError::parse("MOV requires two arguments"));
             }
             Ok(Box::new(MovCommand::new(
-                args[0].to_string(),
-                args[1..].join(" "),
+                args[0].value.clone(),
+                Operand::from_tokens(&args[1..])?,
             )))
         });
 
         registry.register("EXEC", "EXEC", |args| {
             if args.is_empty() {
-                return Err("EXEC requires a command".to_string());
+                return Err(CerealError::parse("EXEC requires a command"));
             }
             Ok(Box::new(ExecCommand::new(args.join(" "))))
         });
 
-        registry.register("FN", "FN", |args| {
-            if args.len() < 2 || args[1] != "DO" {
-                return Err("Function definition must be in format: FN name DO".to_string());
+        registry.register_with_tokens("CALL", "CALL", |args| {
+            if args.is_empty() {
+                return Err(CerealError::parse("CALL requires a function name"));
             }
-            Ok(Box::new(FnDefCommand::new(
-                args[0].to_string(),
-                Vec::new(), // Body will be filled by VM during execution
+
+            // An optional `-> <variable>` at the end captures the returned value
+            let (call_args, result_var) = match args[1..].iter().position(|arg| arg.token_type == TokenType::Arrow) {
+                Some(i) if i + 3 == args.len() => (&args[1..i + 1], Some(args[i + 2].value.clone())),
+                Some(_) => return Err(CerealError::parse("CALL result must be in format: CALL name [args...] -> variable")),
+                None => (&args[1..], None),
+            };
+
+            Ok(Box::new(FnCallCommand::new(
+                args[0].value.clone(),
+                Operand::split_arguments(call_args)?,
+                result_var,
             )))
         });
 
-        registry.register("CALL", "CALL", |args| {
-            if args.is_empty() {
-                return Err("CALL requires a function name".to_string());
-            }
-            Ok(Box::new(FnCallCommand::new(args[0].to_string())))
+        registry.register_with_tokens("RETURN", "RETURN", |args| {
+            Ok(Box::new(ReturnCommand::new(Operand::from_tokens(args)?)))
         });
 
-        registry.register("ENDFN", "ENDFN", |_args| {
-            Ok(Box::new(EndFnCommand::new()))
-        });
-        
         registry.register("INPUT", "INPUT", |args| {
             if args.is_empty() {
-                return Err("INPUT requires a variable name".to_string());
+                return Err(CerealError::parse("INPUT requires a variable name"));
             }
             Ok(Box::new(InputCommand::new(args[0].to_string())))
         });
 
         registry.register("LIBCALL", "LIBCALL", |args| {
             if args.is_empty() {
-                return Err("LIBCALL requires a library name".to_string());
+                return Err(CerealError::parse("LIBCALL requires a library name"));
             }
             Ok(Box::new(LibCallCommand::new(args[0].to_string())))
         });
@@ -83,65 +119,442 @@ impl Parser {
             current_line: 0,
             registry,
             last_args: Vec::new(),
+            source_name: "<input>".to_string(),
+            macros: HashMap::new(),
+            defining: None,
+            import_stack: Vec::new(),
         }
     }
 
-    pub fn parse_line(&mut self, line: &str) -> Result<Option<Box<dyn Command>>, String> {
-        self.current_line += 1;
-        let line = line.trim();
-        
-        if line.is_empty() {
+    /// Sets the file name that errors are reported against
+    pub fn set_source_name(&mut self, name: &str) {
+        self.source_name = name.to_string();
+    }
+
+    /// Parses a whole script into a tree of statements. Each block (IF, WHILE, FOR, TRY and FN)
+    /// holds the statements up to its closing line, calls of user-defined macros are replaced by
+    /// what they expand to and imported files are parsed along with the script.
+    pub fn parse_program(&mut self, source: &str) -> Result<Program, CerealError> {
+        let mut lines = self.split_lines(source)?.into_iter();
+        let (statements, _) = self.parse_block(&mut lines, Nesting::default())?;
+        Ok(Program { statements })
+    }
+
+    /// Lexes the whole source and splits the tokens into lines at each EOL, skipping empty lines
+    fn split_lines(&self, source: &str) -> Result<Vec<Line>, CerealError> {
+        let texts: Vec<&str> = source.lines().collect();
+        let text = |number: usize| texts.get(number - 1).copied().unwrap_or_default();
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.tokenize().map_err(|e| {
+            let span = lexer.error_span();
+            self.error_at(text(span.line), span, e)
+        })?;
+
+        let lines = tokens
+            .split(|token| token.token_type == TokenType::EOL)
+            .filter(|tokens| !tokens.is_empty())
+            .map(|tokens| Line { text: text(tokens[0].span.line).to_string(), tokens: tokens.to_vec() })
+            .collect();
+        Ok(lines)
+    }
+
+    /// Parses statements until a line starting with one of the keywords that end the block,
+    /// which is returned for the caller to handle. Returns no line if the source ran out first.
+    fn parse_block(&mut self, lines: &mut IntoIter<Line>, nesting: Nesting) -> Result<(Vec<Statement>, Option<Line>), CerealError> {
+        let mut statements = Vec::new();
+
+        while let Some(line) = lines.next() {
+            if nesting.ends.contains(&line.keyword()) {
+                return Ok((statements, Some(line)));
+            }
+            self.parse_statement(&line, lines, nesting, &mut statements)
+                .map_err(|e| self.error_at(&line.text, line.tokens[0].span, e))?;
+        }
+        Ok((statements, None))
+    }
+
+    /// Parses the body of a block opened on the given line, up to the line that ends it
+    fn parse_body(&mut self, opener: &Line, lines: &mut IntoIter<Line>, nesting: Nesting, unclosed: &str) -> Result<(Vec<Statement>, Line), CerealError> {
+        match self.parse_block(lines, nesting)? {
+            (body, Some(end)) => Ok((body, end)),
+            (_, None) => Err(self.error_at(&opener.text, opener.tokens[0].span, CerealError::parse(unclosed))),
+        }
+    }
+
+    /// Parses the statement starting on the given line, reading the rest of its block if it opens one.
+    /// A call of a user-defined macro adds each statement it expands to.
+    fn parse_statement(&mut self, line: &Line, lines: &mut IntoIter<Line>, nesting: Nesting, statements: &mut Vec<Statement>) -> Result<(), CerealError> {
+        let tokens = &line.tokens;
+        let location = SourceLocation::new(&self.source_name, tokens[0].span, &line.text);
+
+        if let Some(expansion) = self.expand_macro(tokens, &location)? {
+            statements.extend(expansion);
+            return Ok(());
+        }
+
+        let keyword = line.keyword();
+        let kind = match keyword {
+            "IF" => self.parse_if(line, lines, nesting)?,
+            "WHILE" => {
+                let condition = Expression::parse(&tokens[1..]).map_err(|e| e.context("WHILE"))?;
+                let nesting = nesting.open("WHILE", &["ENDWHILE"]);
+                let (body, _) = self.parse_body(line, lines, nesting, "Unclosed WHILE block")?;
+                StatementKind::While { condition, body }
+            }
+            "FOR" => {
+                let (var, source, mode) = parse_for(&tokens[1..])?;
+                let nesting = nesting.open("FOR", &["ENDFOR"]);
+                let (body, _) = self.parse_body(line, lines, nesting, "Unclosed FOR block")?;
+                StatementKind::For { var, source, mode, body }
+            }
+            "TRY" => self.parse_try(line, lines, nesting)?,
+            "BREAK" | "CONTINUE" if !nesting.in_loop => {
+                return Err(CerealError::parse(format!("{} outside of a loop", keyword)));
+            }
+            "BREAK" => StatementKind::Break,
+            "CONTINUE" => StatementKind::Continue,
+            "FN" => self.parse_function(line, lines, nesting)?,
+            "MACRO" => return self.parse_macro_definition(line, lines, nesting),
+            "IMPORT" => self.parse_import(tokens, nesting)?,
+            "DEFER" => StatementKind::Command(self.parse_defer(tokens, &line.text)?),
+            _ => {
+                if let Some((_, open)) = CLOSING_KEYWORDS.iter().find(|(close, _)| *close == keyword) {
+                    return Err(CerealError::parse(match nesting.block {
+                        Some(block) if block != *open => format!("{} found while {} block is still open", keyword, block),
+                        _ => format!("{} without matching {}", keyword, open),
+                    }));
+                }
+                StatementKind::Command(self.parse_tokens(tokens)?)
+            }
+        };
+
+        statements.push(Statement { kind, location });
+        Ok(())
+    }
+
+    /// Parses `IF <condition>`, any `ELSEIF <condition>` and `ELSE` branches, up to ENDIF
+    fn parse_if(&mut self, line: &Line, lines: &mut IntoIter<Line>, nesting: Nesting) -> Result<StatementKind, CerealError> {
+        let mut branches = Vec::new();
+        let mut condition = Expression::parse(&line.tokens[1..]).map_err(|e| e.context("IF"))?;
+
+        loop {
+            let branch = nesting.open("IF", &["ELSEIF", "ELSE", "ENDIF"]);
+            let (body, end) = self.parse_body(line, lines, branch, "Unclosed IF block")?;
+            branches.push((condition, body));
+
+            match end.keyword() {
+                "ELSEIF" => {
+                    condition = Expression::parse(&end.tokens[1..])
+                        .map_err(|e| self.error_at(&end.text, end.tokens[0].span, e.context("ELSEIF")))?;
+                }
+                "ELSE" => {
+                    let (otherwise, _) = self.parse_body(line, lines, nesting.open("IF", &["ENDIF"]), "Unclosed IF block")?;
+                    return Ok(StatementKind::If { branches, otherwise: Some(otherwise) });
+                }
+                _ => return Ok(StatementKind::If { branches, otherwise: None }),
+            }
+        }
+    }
+
+    /// Parses `TRY`, an optional `CATCH <variable>` and an optional `FINALLY`, up to ENDTRY
+    fn parse_try(&mut self, line: &Line, lines: &mut IntoIter<Line>, nesting: Nesting) -> Result<StatementKind, CerealError> {
+        let (body, mut end) = self.parse_body(line, lines, nesting.open("TRY", &["CATCH", "FINALLY", "ENDTRY"]), "Unclosed TRY block")?;
+
+        let mut catch = None;
+        if end.keyword() == "CATCH" {
+            let var = match &end.tokens[1..] {
+                [var] => var.value.clone(),
+                _ => {
+                    let error = CerealError::parse("CATCH must be in format: CATCH <variable>");
+                    return Err(self.error_at(&end.text, end.tokens[0].span, error));
+                }
+            };
+            let (handler, next) = self.parse_body(line, lines, nesting.open("TRY", &["FINALLY", "ENDTRY"]), "Unclosed TRY block")?;
+            catch = Some(Catch { var, body: handler });
+            end = next;
+        }
+
+        let mut finally = None;
+        if end.keyword() == "FINALLY" {
+            let (cleanup, _) = self.parse_body(line, lines, nesting.open("TRY", &["ENDTRY"]), "Unclosed TRY block")?;
+            finally = Some(cleanup);
+        }
+
+        Ok(StatementKind::Try { body, catch, finally })
+    }
+
+    /// Parses `FN <name> [params...] DO` and the function's body, up to ENDFN.
+    /// Functions can only be defined at the top level of a script.
+    fn parse_function(&mut self, line: &Line, lines: &mut IntoIter<Line>, nesting: Nesting) -> Result<StatementKind, CerealError> {
+        if nesting.in_macro {
+            return Err(CerealError::parse("FN cannot be used inside a macro"));
+        }
+        if let Some(block) = nesting.block {
+            return Err(CerealError::parse(format!("Function definition inside an unclosed {} block", block)));
+        }
+        if nesting.in_function {
+            return Err(CerealError::parse("Function definition inside a function definition"));
+        }
+
+        let (name, params) = parse_definition(&line.tokens[1..])
+            .ok_or_else(|| CerealError::parse("Function definition must be in format: FN name [params...] DO"))?;
+        let nesting = Nesting { ends: &["ENDFN"], in_function: true, ..Nesting::default() };
+        let (body, _) = self.parse_body(line, lines, nesting, "Unclosed function definition")?;
+
+        Ok(StatementKind::Function { name, params, body })
+    }
+
+    /// Parses `MACRO <name> [params...] DO` and the macro's body, up to ENDMACRO, and keeps it
+    /// so later calls of the macro can be expanded. Blocks opened in a macro must be closed in it.
+    fn parse_macro_definition(&mut self, line: &Line, lines: &mut IntoIter<Line>, nesting: Nesting) -> Result<(), CerealError> {
+        if nesting.in_macro {
+            return Err(CerealError::parse("MACRO cannot be used inside a macro"));
+        }
+        if let Some(block) = nesting.block {
+            return Err(CerealError::parse(format!("Macro definition inside an unclosed {} block", block)));
+        }
+        if nesting.in_function {
+            return Err(CerealError::parse("Macro definition inside a function definition"));
+        }
+
+        let (name, params) = parse_definition(&line.tokens[1..])
+            .ok_or_else(|| CerealError::parse("Macro definition must be in format: MACRO name [params...] DO"))?;
+        let nesting = Nesting { ends: &["ENDMACRO"], in_macro: true, ..Nesting::default() };
+
+        self.defining = Some(name.clone());
+        let result = self.parse_body(line, lines, nesting, "Unclosed macro definition");
+        self.defining = None;
+        let (body, _) = result?;
+
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    /// If the tokens call a user-defined macro (`!name args...`), returns the statements it expands to:
+    /// a MOV for each parameter followed by the macro's body. Other macros are left to the libraries.
+    fn expand_macro(&mut self, tokens: &[Token], location: &SourceLocation) -> Result<Option<Vec<Statement>>, CerealError> {
+        let [bang, name, args @ ..] = tokens else {
+            return Ok(None);
+        };
+        if bang.token_type != TokenType::Macro {
             return Ok(None);
         }
+        if self.defining.as_ref() == Some(&name.value) {
+            return Err(CerealError::parse(format!("Macro '!{}' cannot call itself", name.value)));
+        }
+        let Some(definition) = self.macros.get(&name.value).cloned() else {
+            return Ok(None);
+        };
 
-        let mut lexer = Lexer::new(line);
-        let tokens = lexer.tokenize()?;
-        
-        // Log tokens to file
-        if let Ok(mut file) = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("tokens.bin") 
-        {
-            if self.current_line == 0 {
-                writeln!(file, "SOF")
-                    .unwrap_or_else(|e| eprintln!("Failed to write SOF: {}", e));
-            }
-            // Write the value in each token
-            writeln!(file, "Line {}: {} -> Tokens: {:?}", 
-                self.current_line, line, tokens)
-                .unwrap_or_else(|e| eprintln!("Failed to write to tokens.txt: {}", e));
+        // Each argument is a single token, or a whole list or map literal
+        let mut values: Vec<(bool, String)> = Vec::new();
+        let mut depth = 0;
+        for token in args {
+            match token.token_type {
+                TokenType::Symbol('[') | TokenType::Symbol('{') => depth += 1,
+                TokenType::Symbol(']') | TokenType::Symbol('}') if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            match values.last_mut() {
+                Some((open, value)) if *open => {
+                    value.push(' ');
+                    value.push_str(&token.to_source());
+                    *open = depth > 0;
+                }
+                _ => values.push((depth > 0, token.to_source())),
+            }
+        }
+
+        if values.len() != definition.params.len() {
+            return Err(CerealError::parse(format!(
+                "Macro '!{}' expects {} argument(s), got {}",
+                name.value, definition.params.len(), values.len()
+            )));
+        }
+
+        let mut statements = Vec::new();
+        for (param, (_, value)) in definition.params.iter().zip(values) {
+            let command = self.parse_generated_line(&format!("MOV {} {}", param, value))?;
+            statements.push(Statement { kind: StatementKind::Command(command), location: location.clone() });
+        }
+        statements.extend(definition.body);
+        Ok(Some(statements))
+    }
+
+    /// Parses `IMPORT "<path>" [AS <namespace>]`, along with the file it names, relative to the
+    /// importing file. Imported files can only define functions, macros and constants, and their
+    /// macros can be called straight away, as `!namespace.name` when imported into a namespace.
+    fn parse_import(&mut self, tokens: &[Token], nesting: Nesting) -> Result<StatementKind, CerealError> {
+        if nesting.in_macro {
+            return Err(CerealError::parse("IMPORT cannot be used inside a macro"));
+        }
+        if let Some(block) = nesting.block {
+            return Err(CerealError::parse(format!("IMPORT inside an unclosed {} block", block)));
+        }
+        if nesting.in_function {
+            return Err(CerealError::parse("IMPORT inside a function definition"));
+        }
+
+        let namespace = match &tokens[1..] {
+            [path] if path.token_type == TokenType::String => None,
+            [path, keyword, namespace]
+                if path.token_type == TokenType::String
+                    && keyword.value == "AS"
+                    && namespace.token_type == TokenType::Identifier
+                    && !namespace.value.contains('.') =>
+            {
+                Some(namespace.value.clone())
+            }
+            _ => return Err(CerealError::parse("IMPORT must be in format: IMPORT \"file\" [AS namespace]")),
+        };
+
+        let base = match self.source_name.as_str() {
+            "<input>" => Path::new(""),
+            name => Path::new(name).parent().unwrap_or(Path::new("")),
+        };
+        let path = base.join(&tokens[1].value);
+        let script = fs::read_to_string(&path)
+            .map_err(|e| CerealError::io(format!("Failed to import '{}': {}", path.display(), e)))?;
+        let canonical = path.canonicalize()?;
+
+        // The importing file counts too, so a file can't import itself or whatever imports it
+        let mut import_stack = self.import_stack.clone();
+        if import_stack.is_empty() {
+            import_stack.extend(Path::new(&self.source_name).canonicalize().ok());
+        }
+        if import_stack.contains(&canonical) {
+            let cycle: Vec<String> = import_stack
+                .iter()
+                .skip_while(|file| **file != canonical)
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(CerealError::parse(format!("Import cycle: {}", cycle.join(" -> "))));
+        }
+        import_stack.push(canonical);
+
+        let mut parser = Parser::new();
+        parser.set_source_name(&path.display().to_string());
+        parser.import_stack = import_stack;
+        let program = parser.parse_program(&script)?;
+
+        for statement in &program.statements {
+            let name = statement.kind.name();
+            if !matches!(name, "FN" | "DEF" | "IMPORT") {
+                return Err(CerealError::parse(format!(
+                    "{} can't be used at the top level of an imported file, only FN, MACRO, DEF and IMPORT",
+                    name
+                )).with_location(statement.location.clone()));
+            }
+        }
+
+        for (name, definition) in parser.macros {
+            let name = match &namespace {
+                Some(namespace) => format!("{}.{}", namespace, name),
+                None => name,
+            };
+            self.macros.insert(name, definition);
         }
 
+        Ok(StatementKind::Import { path: tokens[1].value.clone(), namespace, program })
+    }
+
+    /// Parses a single line into a command. Blocks and definitions span several lines,
+    /// so they can only be parsed as part of a program with `parse_program`.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<Box<dyn Command>>, CerealError> {
+        self.current_line += 1;
+        self.parse_line_at(line, self.current_line)
+    }
+
+    /// Parses a line whose number in the source file is known, so token spans
+    /// and errors point at the right place. Errors include the file, line and column.
+    pub fn parse_line_at(&mut self, line: &str, number: usize) -> Result<Option<Box<dyn Command>>, CerealError> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        // Lex the untrimmed line so columns match the source
+        let mut lexer = Lexer::with_line(line, number);
+        let tokens = lexer
+            .tokenize()
+            .map_err(|e| self.error_at(line, lexer.error_span(), e))?;
+
         if tokens.is_empty() {
             return Ok(None);
         }
 
         // Store tokens for later use
         self.last_args = tokens.iter().map(|t| t.value.clone()).collect();
- 
+
+        let result = match tokens[0].value.as_str() {
+            keyword if tokens[0].token_type == TokenType::Command && BLOCK_KEYWORDS.contains(&keyword) => {
+                Err(CerealError::parse(format!("{} can only be used as part of a program", keyword)))
+            }
+            "DEFER" if tokens[0].token_type == TokenType::Command => self.parse_defer(&tokens, line),
+            _ => self.parse_tokens(&tokens),
+        };
+        result.map(Some).map_err(|e| self.error_at(line, tokens[0].span, e))
+    }
+
+    /// Points an error at the given position of a source line
+    fn error_at(&self, line: &str, span: Span, error: CerealError) -> CerealError {
+        error.with_location(SourceLocation::new(&self.source_name, span, line))
+    }
+
+    /// Parses a line generated by macro expansion. Errors are left without a location,
+    /// as they are reported against the macro's own line.
+    fn parse_generated_line(&mut self, line: &str) -> Result<Box<dyn Command>, CerealError> {
+        self.current_line += 1;
+        let tokens = Lexer::new(line).tokenize()?;
+        self.parse_command(&tokens)
+    }
+
+    /// Parses the tokens of a simple statement: a command, or a call of a library macro
+    fn parse_tokens(&mut self, tokens: &[Token]) -> Result<Box<dyn Command>, CerealError> {
         match &tokens[0].token_type {
-            TokenType::Macro => self.parse_macro(&tokens),
-            TokenType::Command => self.parse_command(&tokens),
-            _ => Err(format!("Expected command or macro, got {:?}", tokens[0].token_type)),
+            TokenType::Macro => self.parse_macro(tokens),
+            TokenType::Command => self.parse_command(tokens),
+            _ => Err(CerealError::parse(format!("Expected command or macro, got {:?}", tokens[0].token_type))),
         }
     }
 
-    fn parse_command(&mut self, tokens: &[Token]) -> Result<Option<Box<dyn Command>>, String> {
+    fn parse_command(&mut self, tokens: &[Token]) -> Result<Box<dyn Command>, CerealError> {
         let command_name = &tokens[0].value;
-        let args: Vec<&str> = tokens[1..]
-            .iter()
-            .map(|t| t.value.as_str())
-            .collect();
 
-        self.registry
-            .create_command(command_name, args)
-            .map(Some)
+        self.registry.create_command(command_name, &tokens[1..])
     }
 
-    fn parse_macro(&mut self, tokens: &[Token]) -> Result<Option<Box<dyn Command>>, String> {
+    /// Parses `DEFER <statement>`, where the statement is a single command or library macro
+    fn parse_defer(&mut self, tokens: &[Token], line: &str) -> Result<Box<dyn Command>, CerealError> {
+        let statement = &tokens[1..];
+
+        // Deferred commands run on their own, outside of the normal flow of the script
+        match statement {
+            [first, ..] if first.token_type == TokenType::Command
+                && (BLOCK_KEYWORDS.contains(&first.value.as_str()) || first.value == "RETURN") =>
+            {
+                return Err(CerealError::parse(format!("{} cannot be deferred", first.value)));
+            }
+            [first, name, ..] if first.token_type == TokenType::Macro && self.macros.contains_key(&name.value) => {
+                return Err(CerealError::parse(format!("Macro '!{}' cannot be deferred", name.value)));
+            }
+            _ => {}
+        }
+
+        let command = match statement.first().map(|token| &token.token_type) {
+            Some(TokenType::Macro) => self.parse_macro(statement)?,
+            Some(TokenType::Command) if statement[0].value != "DEFER" => self.parse_command(statement)?,
+            _ => return Err(CerealError::parse("DEFER must be followed by a command or macro")),
+        };
+
+        let location = SourceLocation::new(&self.source_name, statement[0].span, line);
+        Ok(Box::new(DeferCommand::new(command, location)))
+    }
+
+    fn parse_macro(&mut self, tokens: &[Token]) -> Result<Box<dyn Command>, CerealError> {
         if tokens.len() < 2 {
-            return Err("Macro requires a name".to_string());
+            return Err(CerealError::parse("Macro requires a name"));
         }
 
         let macro_name = &tokens[1].value;
@@ -154,19 +567,17 @@ impl Parser {
         self.generate_mov_commands(&args, &mut expanded_commands)?;
         self.add_libcall_command(macro_name, &mut expanded_commands)?;
 
-        Ok(Some(Box::new(MultiCommand::new(expanded_commands))))
+        Ok(Box::new(MultiCommand::new(expanded_commands)))
     }
 
     fn generate_mov_commands(
         &mut self,
         args: &[&str],
         commands: &mut Vec<Box<dyn Command>>
-    ) -> Result<(), String> {
+    ) -> Result<(), CerealError> {
         for (i, arg) in args.iter().enumerate() {
             let mov_cmd = format!("MOV r{} {}", i, arg);
-            if let Some(command) = self.parse_line(&mov_cmd)? {
-                commands.push(command);
-            }
+            commands.push(self.parse_generated_line(&mov_cmd)?);
         }
         Ok(())
     }
@@ -175,14 +586,13 @@ impl Parser {
         &mut self,
         macro_name: &str,
         commands: &mut Vec<Box<dyn Command>>
-    ) -> Result<(), String> {
+    ) -> Result<(), CerealError> {
         let libcall_cmd = format!("LIBCALL {}", macro_name);
-        if let Some(command) = self.parse_line(&libcall_cmd)? {
-            commands.push(command);
-        }
+        commands.push(self.parse_generated_line(&libcall_cmd)?);
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn get_last_args(&self) -> Option<Vec<String>> {
         if self.last_args.is_empty() {
             None
@@ -191,3 +601,78 @@ impl Parser {
         }
     }
 }
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A line of the program being parsed, with its tokens
+struct Line {
+    text: String,
+    tokens: Vec<Token>,
+}
+
+impl Line {
+    /// The command the line starts with, or an empty string if it doesn't start with one
+    fn keyword(&self) -> &str {
+        match self.tokens.first() {
+            Some(token) if token.token_type == TokenType::Command => &token.value,
+            _ => "",
+        }
+    }
+}
+
+/// Where the statements being parsed are, which decides the keywords allowed in them
+#[derive(Clone, Copy, Default)]
+struct Nesting {
+    block: Option<&'static str>,        // Innermost IF, WHILE, FOR or TRY block that is open
+    ends: &'static [&'static str],      // Keywords that end the statements being parsed
+    in_loop: bool,
+    in_function: bool,
+    in_macro: bool,
+}
+
+impl Nesting {
+    /// The nesting inside a block opened here, which ends at one of the given keywords
+    fn open(self, block: &'static str, ends: &'static [&'static str]) -> Self {
+        Nesting {
+            block: Some(block),
+            ends,
+            in_loop: self.in_loop || block == "WHILE" || block == "FOR",
+            ..self
+        }
+    }
+}
+
+/// Parses `<name> [params...] DO`, the rest of a FN or MACRO line
+fn parse_definition(tokens: &[Token]) -> Option<(String, Vec<String>)> {
+    match tokens {
+        [name, params @ .., keyword] if keyword.value == "DO" => {
+            Some((name.value.clone(), params.iter().map(|param| param.value.clone()).collect()))
+        }
+        _ => None,
+    }
+}
+
+/// Parses `<variable> IN <value> [LINES|WORDS|SPLIT <delimiter>] DO`, the rest of a FOR line,
+/// where lists and maps need no mode
+fn parse_for(args: &[Token]) -> Result<(String, String, ForMode), CerealError> {
+    let usage = || CerealError::parse("FOR must be in format: FOR <variable> IN <value> [LINES|WORDS|SPLIT <delimiter>] DO");
+    let is_keyword = |token: Option<&Token>, keyword: &str| {
+        token.is_some_and(|token| token.token_type == TokenType::Identifier && token.value == keyword)
+    };
+
+    if args.len() < 4 || !is_keyword(args.get(1), "IN") || !is_keyword(args.last(), "DO") {
+        return Err(usage());
+    }
+    let mode = match (args[3].value.as_str(), args.len()) {
+        (_, 4) => ForMode::Items,
+        ("LINES", 5) => ForMode::Lines,
+        ("WORDS", 5) => ForMode::Words,
+        ("SPLIT", 6) => ForMode::Split(args[4].value.clone()),
+        _ => return Err(usage()),
+    };
+    Ok((args[0].value.clone(), args[2].value.clone(), mode))
+}
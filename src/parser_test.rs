@@ -2,8 +2,10 @@
 mod tests {
     use crate::parser::Parser;
     use crate::command::Command;
+    use crate::error::CerealError;
+    use crate::ast::{Statement, StatementKind};
 
-    fn assert_command_name(result: Result<Option<Box<dyn Command>>, String>, expected_name: &str) {
+    fn assert_command_name(result: Result<Option<Box<dyn Command>>, CerealError>, expected_name: &str) {
         match result {
             Ok(Some(command)) => assert_eq!(command.name(), expected_name),
             Ok(None) => panic!("Expected command, got None"),
@@ -11,7 +13,7 @@ mod tests {
         }
     }
 
-    fn assert_is_none(result: Result<Option<Box<dyn Command>>, String>) {
+    fn assert_is_none(result: Result<Option<Box<dyn Command>>, CerealError>) {
         match result {
             Ok(None) => (),  // This is what we want
             Ok(Some(command)) => panic!("Expected None, got command: {}", command.name()),
@@ -19,6 +21,10 @@ mod tests {
         }
     }
 
+    fn statement_names(statements: &[Statement]) -> Vec<&str> {
+        statements.iter().map(|statement| statement.kind.name()).collect()
+    }
+
     #[test]
     fn test_empty_line() {
         let mut parser = Parser::new();
@@ -52,6 +58,128 @@ mod tests {
         assert_eq!(result.name(), "MULTI");
     }
 
+    #[test]
+    fn test_function_definition_and_call() {
+        let mut parser = Parser::new();
+
+        let program = parser.parse_program("FN greet DO\nENDFN\nFN greet name greeting DO\n    RETURN $name\nENDFN").unwrap();
+        assert_eq!(statement_names(&program.statements), ["FN", "FN"]);
+        match &program.statements[1].kind {
+            StatementKind::Function { name, params, body } => {
+                assert_eq!(name, "greet");
+                assert_eq!(params, &["name", "greeting"]);
+                assert_eq!(statement_names(body), ["RETURN"]);
+            }
+            _ => panic!("Expected a function definition"),
+        }
+        assert_command_name(parser.parse_line("CALL greet \"Bob\" \"Hi\""), "CALL");
+        assert_command_name(parser.parse_line("CALL greet \"Bob\" -> result"), "CALL");
+        assert_command_name(parser.parse_line("RETURN $value"), "RETURN");
+
+        assert!(parser.parse_line("FN greet DO").is_err());
+        assert!(parser.parse_program("FN greet name\nENDFN").is_err());
+        assert!(parser.parse_line("CALL greet -> result extra").is_err());
+    }
+
+    #[test]
+    fn test_defer() {
+        let mut parser = Parser::new();
+
+        assert_command_name(parser.parse_line("DEFER EXEC rm /tmp/cereal.txt"), "DEFER");
+        assert_command_name(parser.parse_line("DEFER !writef \"out.txt\" \"done\""), "DEFER");
+        assert_command_name(parser.parse_line("DEFER CALL cleanup \"tmp\""), "DEFER");
+
+        assert!(parser.parse_line("DEFER").is_err());
+        assert!(parser.parse_line("DEFER DEFER PRINT \"x\"").is_err());
+        assert!(parser.parse_line("DEFER WHILE $a IS 1").is_err());
+        assert!(parser.parse_line("DEFER RETURN 1").is_err());
+    }
+
+    #[test]
+    fn test_compound_conditions() {
+        let mut parser = Parser::new();
+        let valid = [
+            "IF $a IS \"1\"",
+            "IF ($status IS \"200\" AND $body CONTAINS \"ok\") OR $force IS \"1\"\nELSEIF NOT $a IS \"AND\"",
+            "WHILE NOT ($n GTE 3 OR $done IS \"yes\")",
+        ];
+        for header in valid {
+            let end = if header.starts_with("WHILE") { "ENDWHILE" } else { "ENDIF" };
+            assert!(parser.parse_program(&format!("{}\n{}", header, end)).is_ok(), "{}", header);
+        }
+
+        for condition in ["", "$a IS", "($a IS \"1\"", "$a IS \"1\" AND", "$a EQUALS \"1\""] {
+            assert!(parser.parse_program(&format!("IF {}\nENDIF", condition)).is_err(), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let mut parser = Parser::new();
+        let script = r#"
+            MOV n 0
+            WHILE $n LT 3
+                ADD n 1
+                IF $n IS 2
+                    CONTINUE
+                ELSEIF $n IS 5
+                    BREAK
+                ELSE
+                    PRINT $n
+                ENDIF
+            ENDWHILE
+            TRY
+                FOR item IN $items DO
+                    PRINT $item
+                ENDFOR
+            CATCH err
+                PRINT $err
+            FINALLY
+                PRINT "done"
+            ENDTRY
+        "#;
+
+        let program = parser.parse_program(script).unwrap();
+        assert_eq!(statement_names(&program.statements), ["MOV", "WHILE", "TRY"]);
+        assert_eq!(program.statements[1].location.line, 3);
+
+        let StatementKind::While { body, .. } = &program.statements[1].kind else {
+            panic!("Expected a WHILE loop");
+        };
+        assert_eq!(statement_names(body), ["ADD", "IF"]);
+        let StatementKind::If { branches, otherwise } = &body[1].kind else {
+            panic!("Expected an IF block");
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(statement_names(&branches[0].1), ["CONTINUE"]);
+        assert_eq!(statement_names(&branches[1].1), ["BREAK"]);
+        assert_eq!(statement_names(otherwise.as_ref().unwrap()), ["PRINT"]);
+
+        let StatementKind::Try { body, catch, finally } = &program.statements[2].kind else {
+            panic!("Expected a TRY block");
+        };
+        assert_eq!(statement_names(body), ["FOR"]);
+        assert_eq!(catch.as_ref().unwrap().var, "err");
+        assert_eq!(statement_names(finally.as_ref().unwrap()), ["PRINT"]);
+    }
+
+    #[test]
+    fn test_parse_program_errors() {
+        let invalid = [
+            ("IF 1 IS 1\nWHILE 1 IS 1\nENDIF\nENDWHILE", "<input>:3:1: ENDIF found while WHILE block is still open"),
+            ("MOV a 1\n  FOR x IN $a DO\n", "<input>:2:3: Unclosed FOR block"),
+            ("BREAK", "<input>:1:1: BREAK outside of a loop"),
+            ("FN f DO\n    CONTINUE\nENDFN", "<input>:2:5: CONTINUE outside of a loop"),
+            ("ELSE", "<input>:1:1: ELSE without matching IF"),
+            ("IF 1 IS 1\n  FN f DO\n  ENDFN\nENDIF", "<input>:2:3: Function definition inside an unclosed IF block"),
+            ("TRY\nCATCH\nENDTRY", "<input>:2:1: CATCH must be in format: CATCH <variable>"),
+        ];
+        for (script, expected) in invalid {
+            let error = Parser::new().parse_program(script).err().expect(script).to_string();
+            assert!(error.starts_with(expected), "{}", error);
+        }
+    }
+
     #[test]
     fn test_invalid_commands() {
         let mut parser = Parser::new();
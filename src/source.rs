@@ -0,0 +1,44 @@
+/// Position of a token in the source, both 1-based
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A position in a named source file, along with the text of its line,
+/// so errors can show the offending code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+}
+
+impl SourceLocation {
+    pub fn new(file: &str, span: Span, text: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            line: span.line,
+            column: span.column,
+            text: text.to_string(),
+        }
+    }
+
+    /// Formats an error as `file:line:col: message`, followed by the source line
+    /// and a caret under the column
+    pub fn format_error(&self, message: &str) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Tabs would throw the caret out of line with the text above it
+        let text = self.text.replace('\t', " ");
+        let caret = " ".repeat(self.column.saturating_sub(1));
+
+        format!(
+            "{}:{}:{}: {}\n {} | {}\n {} | {}^",
+            self.file, self.line, self.column, message,
+            number, text,
+            gutter, caret
+        )
+    }
+}
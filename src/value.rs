@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::command::ExecutionContext;
+use crate::error::CerealError;
+use crate::lexer::{Token, TokenType};
+use crate::number::Number;
+
+/// A value stored in a variable.
+/// Lists and maps are written with literals such as `["a", "b"]` and `{"port": 8080}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Checks if the tokens of an argument are a literal, rather than text to expand
+    pub fn is_literal(tokens: &[Token]) -> bool {
+        match tokens {
+            [token] => {
+                token.token_type == TokenType::Number
+                    || (token.token_type == TokenType::Identifier && ["true", "false", "null"].contains(&token.value.as_str()))
+            }
+            [first, ..] => matches!(first.token_type, TokenType::Symbol('[') | TokenType::Symbol('{')),
+            [] => false,
+        }
+    }
+
+    /// Parses a literal, returning an error if any tokens are left over
+    pub fn parse(tokens: &[Token]) -> Result<Value, CerealError> {
+        let mut parser = LiteralParser { tokens, position: 0 };
+        let value = parser.parse_value()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(CerealError::parse(format!("Unexpected '{}' after value", token.value)));
+        }
+        Ok(value)
+    }
+
+    /// Looks up an item of a list by position, or an entry of a map by key
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::List(items) => items.get(key.parse::<usize>().ok()?),
+            Value::Map(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    /// Reads the value as a number, parsing its text unless it already holds an integer
+    pub fn to_number(&self) -> Result<Number, String> {
+        match self {
+            Value::Number(Number::Int(int)) => Ok(Number::Int(*int)),
+            _ => Number::parse(&self.to_string()),
+        }
+    }
+
+    /// Formats the value the way it would be written in a literal, e.g. with strings quoted
+    pub fn to_literal(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::String(text) => format!("{:?}", text),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Lists and maps are shown as literals, `null` as an empty string and everything else as its text
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::String(text) => write!(f, "{}", text),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(Value::to_literal).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{:?}: {}", key, value.to_literal()))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::String(text)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::String(text.to_string())
+    }
+}
+
+impl From<Number> for Value {
+    fn from(number: Number) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(int: i64) -> Self {
+        Value::Number(Number::Int(int))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Value::Number(Number::Float(float))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+/// The value given to a command such as MOV: a literal parsed when the script is loaded,
+/// or text that is expanded when the command runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Literal(Value),
+    Text(String),
+}
+
+impl Operand {
+    /// Builds an operand from the tokens of an argument, joining non-literal tokens with spaces
+    pub fn from_tokens(tokens: &[Token]) -> Result<Operand, CerealError> {
+        if Value::is_literal(tokens) {
+            return Ok(Operand::Literal(Value::parse(tokens)?));
+        }
+        let words: Vec<&str> = tokens.iter().map(|token| token.value.as_str()).collect();
+        Ok(Operand::Text(words.join(" ")))
+    }
+
+    /// Builds an operand from text to expand. Text without a `$` reads the same every time,
+    /// so it's kept as a literal, as a number if that's how it would be written.
+    pub fn from_text(text: &str) -> Operand {
+        if text.contains('$') {
+            return Operand::Text(text.to_string());
+        }
+        match Number::parse(text) {
+            Ok(number) if number.to_string() == text => Operand::Literal(Value::Number(number)),
+            _ => Operand::Literal(Value::String(text.to_string())),
+        }
+    }
+
+    /// Builds one operand per argument, where a list or map literal counts as a single argument
+    pub fn split_arguments(tokens: &[Token]) -> Result<Vec<Operand>, CerealError> {
+        let mut arguments = Vec::new();
+        let mut start = 0;
+        let mut depth = 0;
+
+        for (position, token) in tokens.iter().enumerate() {
+            match token.token_type {
+                TokenType::Symbol('[') | TokenType::Symbol('{') => depth += 1,
+                TokenType::Symbol(']') | TokenType::Symbol('}') if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                arguments.push(Operand::from_tokens(&tokens[start..=position])?);
+                start = position + 1;
+            }
+        }
+
+        if start < tokens.len() {
+            return Err(CerealError::parse("Missing ']' or '}' at the end of the value"));
+        }
+        Ok(arguments)
+    }
+
+    /// Formats the operand the way it would be written in a script
+    pub fn to_source(&self) -> String {
+        match self {
+            Operand::Literal(value) => value.to_literal(),
+            Operand::Text(text) => quote(text),
+        }
+    }
+
+    pub fn evaluate(&self, context: &ExecutionContext) -> Result<Value, CerealError> {
+        match self {
+            Operand::Literal(value) => Ok(value.clone()),
+            Operand::Text(text) => context.evaluate(text),
+        }
+    }
+}
+
+/// Formats text as a single argument: a name, number or variable reference is written as it is,
+/// anything else as a string literal
+pub fn quote(text: &str) -> String {
+    let is_name = text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !["true", "false", "null"].contains(&text);
+    let is_reference = text.starts_with('$')
+        && text.len() > 1
+        && !text.contains(|c: char| c.is_whitespace() || c == '"');
+    let is_number = !text.contains(char::is_whitespace) && Number::parse(text).is_ok();
+    if is_name || is_reference || is_number {
+        return text.to_string();
+    }
+    Token { token_type: TokenType::String, value: text.to_string(), span: Default::default() }.to_source()
+}
+
+/// Recursive descent parser over the tokens of a literal
+struct LiteralParser<'t> {
+    tokens: &'t [Token],
+    position: usize,
+}
+
+impl<'t> LiteralParser<'t> {
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'t Token> {
+        let token = self.peek()?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /// Consumes the next token if it is the given symbol
+    fn eat(&mut self, symbol: char) -> bool {
+        let found = self.peek().is_some_and(|token| token.token_type == TokenType::Symbol(symbol));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), CerealError> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => Err(CerealError::parse(format!("Expected '{}', found '{}'", symbol, token.value))),
+            None => Err(CerealError::parse(format!("Expected '{}' at the end of the value", symbol))),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, CerealError> {
+        let token = self.next().ok_or_else(|| CerealError::parse("Expected a value"))?;
+
+        match &token.token_type {
+            TokenType::String => Ok(Value::String(token.value.clone())),
+            TokenType::Number => Number::parse(&token.value).map(Value::Number).map_err(CerealError::parse),
+            TokenType::Identifier if token.value == "true" => Ok(Value::Bool(true)),
+            TokenType::Identifier if token.value == "false" => Ok(Value::Bool(false)),
+            TokenType::Identifier if token.value == "null" => Ok(Value::Null),
+            TokenType::Symbol('[') => self.parse_list(),
+            TokenType::Symbol('{') => self.parse_map(),
+            _ => Err(CerealError::parse(format!("Expected a value, found '{}'", token.value))),
+        }
+    }
+
+    /// Parses the rest of a list after its `[`
+    fn parse_list(&mut self) -> Result<Value, CerealError> {
+        let mut items = Vec::new();
+        while !self.eat(']') {
+            if !items.is_empty() {
+                self.expect(',')?;
+            }
+            items.push(self.parse_value()?);
+        }
+        Ok(Value::List(items))
+    }
+
+    /// Parses the rest of a map after its `{`. Keys may be quoted or bare words.
+    fn parse_map(&mut self) -> Result<Value, CerealError> {
+        let mut entries = BTreeMap::new();
+        while !self.eat('}') {
+            if !entries.is_empty() {
+                self.expect(',')?;
+            }
+            let key = match self.next() {
+                Some(token) if matches!(token.token_type, TokenType::String | TokenType::Identifier) => token.value.clone(),
+                Some(token) => return Err(CerealError::parse(format!("Expected a map key, found '{}'", token.value))),
+                None => return Err(CerealError::parse("Expected '}' at the end of the value")),
+            };
+            self.expect(':')?;
+            entries.insert(key, self.parse_value()?);
+        }
+        Ok(Value::Map(entries))
+    }
+}
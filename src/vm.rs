@@ -1,21 +1,55 @@
 #![allow(dead_code)]
 
-use crate::command::Command;
 use crate::parser::Parser;
 use crate::command::ExecutionContext;
+use crate::ast::{Program, StatementKind};
+use crate::bytecode::{Chunk, Op};
+use crate::compiler::{self, Compiler};
+use crate::commands::{ExecCommand, GlobalCommand, InputCommand};
+use crate::libraries::LibraryExecutor;
+use crate::error::CerealError;
+use crate::value::Value;
+use crate::filters::FilterFn;
+use crate::consts::ScriptVariables;
 use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A user-defined function: the names of its parameters and its compiled body,
+/// compiled once when the script is loaded.
+#[derive(Clone)]
+pub struct Function {
+    pub params: Vec<String>,
+    pub code: Chunk,
+    // Namespace the function was imported into, e.g. "utils" for utils.deploy
+    pub namespace: Option<String>,
+}
+
+/// Everything loading a script leaves in the VM, which `cereal build` saves
+/// so the script can be run again without parsing it
+pub struct CompiledProgram {
+    pub main: Chunk,
+    pub functions: Vec<(String, Function)>,
+    // Variables set while loading, e.g. the DEFs of imported files
+    pub globals: Vec<(String, Value)>,
+}
+
+/// Where to go when an error is raised inside a TRY block, and what to discard on the way
+struct Handler {
+    target: usize,
+    stack: usize,   // Values on the stack when the TRY block started
+    errors: usize,  // Errors being handled when the TRY block started
+}
 
 pub struct VM {
-    commands: Vec<Box<dyn Command>>,
+    // Compiled top level of the loaded scripts
+    main: Chunk,
     context: ExecutionContext<'static>,
     parser: Parser,
+    // Shared with each call, so calling a function doesn't copy its body
     #[cfg(test)]
-    pub functions: HashMap<String, Vec<String>>,  // Make public for tests
+    pub functions: HashMap<String, Rc<Function>>,  // Make public for tests
     #[cfg(not(test))]
-    functions: HashMap<String, Vec<String>>,
-    call_stack: Vec<usize>,
-    current_line: usize,
-    current_fn: Option<(String, Vec<String>)>,
+    functions: HashMap<String, Rc<Function>>,
     registers: HashMap<String, String>,
 }
 
@@ -23,15 +57,23 @@ impl VM {
     pub fn new() -> Self {
         // Display boot screen
         Self::display_boot_screen();
-        
+        Self::quiet()
+    }
+
+    /// Creates a VM without showing the boot screen, for output that other tools read, e.g. `--emit`
+    pub fn quiet() -> Self {
+        // Scripts read the environment through $env, e.g. $env.HOME
+        let mut context = ExecutionContext::new();
+        let environment = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, Value::String(value.into_string().ok()?))))
+            .collect();
+        context.set_variable(ScriptVariables::ENV.to_string(), Value::Map(environment));
+
         VM {
-            commands: Vec::new(),
-            context: ExecutionContext::new(),
+            main: Chunk::new(),
+            context,
             parser: Parser::new(),
             functions: HashMap::new(),
-            call_stack: Vec::new(),
-            current_line: 0,
-            current_fn: None,
             registers: HashMap::new(),
         }
     }
@@ -48,172 +90,446 @@ impl VM {
      println!("[VM] The VM is ready to go!");
     }
 
-    pub fn execute(&mut self) -> Result<(), String> {
-        // First, collect all the commands and their args
-        let mut command_data: Vec<(Box<dyn Command>, Vec<String>)> = Vec::new();
-        for command in &self.commands {
-            let args = self.parser.get_last_args().unwrap_or_default();
-            command_data.push((command.box_clone(), args));
+    /// Executes the loaded scripts. Errors point at the statement that failed,
+    /// including statements inside called functions.
+    pub fn execute(&mut self) -> Result<(), CerealError> {
+        let main = std::mem::take(&mut self.main);
+
+        // Move the VM state into a context that can reach back into the VM (e.g. for CALL)
+        let variables = std::mem::take(&mut self.context.variables);
+        let allow_undefined = self.context.allow_undefined;
+        let filters = self.context.filters.clone();
+        let mut context = ExecutionContext::with_vm(self);
+        context.variables = variables;
+        context.allow_undefined = allow_undefined;
+        context.filters = filters;
+
+        context.push_defer_frame();
+        let result = Self::run_chunk(&main, &mut context).map(|_| ());
+        let result = Self::run_deferred(&mut context, result);
+
+        // Now update VM state
+        let variables = std::mem::take(&mut context.variables);
+        self.context.variables = variables;
+        self.main = main;
+
+        result
+    }
+
+    /// Runs compiled code until it ends or reaches RETURN, returning the value passed to RETURN.
+    /// Errors get the location of the instruction that raised them and go to the innermost
+    /// TRY block, if there is one.
+    pub fn run_chunk(chunk: &Chunk, context: &mut ExecutionContext) -> Result<Option<Value>, CerealError> {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut handlers: Vec<Handler> = Vec::new();
+        let mut errors: Vec<CerealError> = Vec::new();
+        let libraries = LibraryExecutor::new();
+        let mut pc = 0;
+
+        while pc < chunk.ops.len() {
+            let position = pc;
+            pc += 1;
+
+            let result = match &chunk.ops[position] {
+                Op::Load(operand) => operand.evaluate(context).map(|value| stack.push(value)),
+                Op::Store(name) => {
+                    let value = stack.pop().unwrap_or(Value::Null);
+                    context.assign(name, value);
+                    Ok(())
+                }
+                Op::Move(name, operand) => operand.evaluate(context).map(|value| context.set_variable(name.clone(), value)),
+                Op::Pop => {
+                    stack.pop();
+                    Ok(())
+                }
+                Op::Compare(comparison) => {
+                    let right = stack.pop().unwrap_or(Value::Null);
+                    let left = stack.pop().unwrap_or(Value::Null);
+                    comparison.apply(&left, &right).map(|holds| stack.push(Value::Bool(holds)))
+                }
+                Op::Not => {
+                    let holds = matches!(stack.pop(), Some(Value::Bool(true)));
+                    stack.push(Value::Bool(!holds));
+                    Ok(())
+                }
+                Op::Jump(target) => {
+                    pc = *target;
+                    Ok(())
+                }
+                Op::JumpIfFalse(target) => {
+                    if matches!(stack.pop(), Some(Value::Bool(false))) {
+                        pc = *target;
+                    }
+                    Ok(())
+                }
+                // The items are kept in reverse, so each one is taken from the end
+                Op::Items(mode, source) => mode.items(source, context).map(|mut items| {
+                    items.reverse();
+                    stack.push(Value::List(items));
+                }),
+                Op::Next(var, target) => {
+                    match stack.last_mut() {
+                        Some(Value::List(items)) => match items.pop() {
+                            Some(item) => context.assign(var, item),
+                            None => pc = *target,
+                        },
+                        _ => pc = *target,
+                    }
+                    Ok(())
+                }
+                Op::Try(target) => {
+                    handlers.push(Handler { target: *target, stack: stack.len(), errors: errors.len() });
+                    Ok(())
+                }
+                Op::EndTry => {
+                    handlers.pop();
+                    Ok(())
+                }
+                // CATCH handles any error except an ABORT, which always stops the script
+                Op::Catch(var, target) => {
+                    match errors.last() {
+                        Some(CerealError::Aborted { .. }) | None => pc = *target,
+                        Some(_) => {
+                            if let Some(error) = errors.pop() {
+                                context.set_variable(var.clone(), error.message());
+                                context.set_variable(format!("{}_kind", var), error.kind().to_string());
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+                Op::Rethrow => match errors.pop() {
+                    Some(error) => Err(error),
+                    None => Ok(()),
+                },
+                Op::Call(name, count) => {
+                    let args = stack.split_off(stack.len().saturating_sub(*count));
+                    Self::call_function(context, name, args).map(|value| stack.push(value.unwrap_or(Value::Null)))
+                }
+                Op::Ret => return Ok(stack.pop()),
+                Op::LibCall(name) => libraries.execute(name, context),
+                Op::Defer(code) => context.defer(code.clone()),
+                Op::Global(names) => {
+                    GlobalCommand::run(context, names);
+                    Ok(())
+                }
+                Op::Arithmetic(operator) => {
+                    let right = stack.pop().unwrap_or(Value::Null);
+                    let left = stack.pop().unwrap_or(Value::Null);
+                    operator.apply(&left, &right).map(|result| stack.push(Value::Number(result)))
+                }
+                Op::Update(operator, target) => {
+                    let operand = stack.pop().unwrap_or(Value::Null);
+                    operator.update(context, target, &operand)
+                }
+                Op::Print => {
+                    println!("{}", stack.pop().unwrap_or(Value::Null));
+                    Ok(())
+                }
+                Op::Exec => {
+                    let command = stack.pop().unwrap_or(Value::Null).to_string();
+                    ExecCommand::run(context, &command)
+                }
+                Op::Input(var) => InputCommand::run(context, var),
+                // Changes the VM's own environment, so commands run with EXEC inherit it
+                Op::SetEnv(name) => {
+                    let value = stack.pop().unwrap_or(Value::Null).to_string();
+                    context.set_env(name, Some(value));
+                    Ok(())
+                }
+                Op::RemoveEnv(name) => {
+                    context.set_env(name, None);
+                    Ok(())
+                }
+                // Stops by returning an error, so whoever is running the VM decides what happens next
+                Op::Abort(code) => {
+                    let message = stack.pop().unwrap_or(Value::Null).to_string();
+                    Err(CerealError::aborted(message, *code))
+                }
+            };
+
+            if let Err(error) = result {
+                let error = match chunk.location(position) {
+                    Some(location) => error.with_location(location.clone()),
+                    None => error,
+                };
+                let Some(handler) = handlers.pop() else {
+                    return Err(error);
+                };
+                stack.truncate(handler.stack);
+                errors.truncate(handler.errors);
+                errors.push(error);
+                pc = handler.target;
+            }
         }
+        Ok(None)
+    }
 
-        // Now process each command
-        for (command, args) in command_data {
-            // Clone the state we need
-            let vars = self.context.variables.clone();
-            let skip = self.context.skip_until.clone();
-            
-            // Execute the command and get the resulting state
-            let (new_vars, new_skip) = {
-                let mut context = ExecutionContext::with_vm(self);
-                context.variables = vars;
-                context.skip_until = skip;
-                context.set_args(args);
-                
-                // Only execute if we're not skipping or if it's a control flow command
-                if !context.should_skip(command.name()) || command.is_control_flow() {
-                    command.execute(&mut context)?;
-                }
-                
-                (context.variables, context.skip_until)
-            }; // context is dropped here, releasing the borrow on self
-            
-            // Now update VM state
-            self.context.variables = new_vars;
-            self.context.skip_until = new_skip;
+    /// Runs the statements deferred by the frame that just ended, most recent first.
+    /// Every deferred statement runs, even after an error; the first error is returned.
+    fn run_deferred(context: &mut ExecutionContext, result: Result<(), CerealError>) -> Result<(), CerealError> {
+        let mut result = result;
+        for chunk in context.pop_defer_frame().into_iter().rev() {
+            if let Err(e) = Self::run_chunk(&chunk, context) {
+                result = result.and(Err(e));
+            }
         }
-        Ok(())
+        result
     }
 
-    /// Adds a command to the VM's command list for later execution.
-    pub fn add_command(&mut self, command: Box<dyn Command>) {
-        self.commands.push(command);
+    /// Removes the loaded statements, keeping variables, functions and macros.
+    /// Lets the REPL run each batch of input once, even if the previous batch failed.
+    pub fn clear_commands(&mut self) {
+        self.main = Chunk::new();
     }
 
     /// Executes a single instruction line immediately.
     /// Useful for direct command execution outside of normal program flow.
     #[allow(dead_code)]
-    pub fn execute_instruction(&mut self, instruction: &str) -> Result<(), String> {
-        let mut parser = Parser::new();
-        if let Some(command) = parser.parse_line(instruction)? {
-            command.execute(&mut self.context)?;
+    pub fn execute_instruction(&mut self, instruction: &str) -> Result<(), CerealError> {
+        Self::run_line(&mut Parser::new(), instruction, &mut self.context)
+    }
+
+    /// Parses a single line, compiles it and runs it straight away
+    fn run_line(parser: &mut Parser, line: &str, context: &mut ExecutionContext) -> Result<(), CerealError> {
+        match parser.parse_line(line)? {
+            Some(command) => Self::run_chunk(&compiler::compile_line(command.as_ref()), context).map(|_| ()),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    /// Loads and parses a script from a string, processing each line.
-    /// Returns an error if there are any parsing issues or unclosed function definitions.
-    pub fn load_string(&mut self, script: &str) -> Result<(), String> {
-        let parser = Parser::new();
-        self.parser = parser.clone();
-        
-        for line in script.lines() {
-            let line = line.trim();
-            if !line.is_empty() {
-                self.process_line(line)?;
+    /// Loads and parses a script from a string.
+    /// Returns an error if there are any parsing issues, such as unclosed blocks or function definitions.
+    pub fn load_string(&mut self, script: &str) -> Result<(), CerealError> {
+        self.load_source("<input>", script)
+    }
+
+    /// Loads and parses a script, reporting errors against the given file name.
+    /// Nothing is loaded if the script doesn't parse.
+    pub fn load_source(&mut self, name: &str, script: &str) -> Result<(), CerealError> {
+        self.parser.set_source_name(name);
+        let program = self.parser.parse_program(script)?;
+        self.load_program(program)
+    }
+
+    /// Adds a parsed program to the VM: functions are compiled and defined, imported files are loaded
+    /// and the remaining statements are compiled and queued to run.
+    fn load_program(&mut self, program: Program) -> Result<(), CerealError> {
+        let mut statements = Vec::new();
+        for statement in program.statements {
+            match statement.kind {
+                StatementKind::Function { name, params, body } => {
+                    let code = Compiler::compile(&body);
+                    self.functions.insert(name, Rc::new(Function { params, code, namespace: None }));
+                }
+                StatementKind::Import { namespace, program, .. } => {
+                    self.load_import(namespace, program)
+                        .map_err(|e| e.with_location(statement.location))?;
+                }
+                _ => statements.push(statement),
             }
         }
+        self.main.append(Compiler::compile(&statements));
+        Ok(())
+    }
+
+    /// Loads an imported file. Its functions are added to the VM and its DEFs are set straight away;
+    /// with `AS <namespace>` functions are called as `namespace.name` and DEFs are read as `$namespace.name`.
+    fn load_import(&mut self, namespace: Option<String>, program: Program) -> Result<(), CerealError> {
+        // Load the file on its own, keeping the state of the file that imports it
+        let main = std::mem::take(&mut self.main);
+        let functions = std::mem::take(&mut self.functions);
 
-        if self.current_fn.is_some() {
-            return Err("Unclosed function definition".to_string());
+        // DEFs of a namespaced import, including those of files it imports, are collected in a scope
+        if namespace.is_some() {
+            self.context.push_scope();
         }
+        let result = self.load_program(program).and_then(|_| self.run_imported_defs());
 
-        Ok(())
-    }
+        let imported = std::mem::replace(&mut self.functions, functions);
+        self.main = main;
+        let scope = match namespace {
+            Some(_) => self.context.scopes.pop(),
+            None => None,
+        };
+        result?;
+
+        let Some(namespace) = namespace else {
+            self.functions.extend(imported);
+            return Ok(());
+        };
 
-    /// Processes a single line of code, parsing it into a command and handling it.
-    /// Returns an error if parsing or command handling fails.
-    fn process_line(&mut self, line: &str) -> Result<(), String> {
-        if let Some(command) = self.parser.parse_line(line)? {
-            self.handle_command(command, line)?;
+        for (name, mut function) in imported {
+            let definition = Rc::make_mut(&mut function);
+            definition.namespace = Some(match definition.namespace.take() {
+                Some(inner) => format!("{}.{}", namespace, inner),
+                None => namespace.clone(),
+            });
+            self.functions.insert(format!("{}.{}", namespace, name), function);
         }
+        let mut entries = match self.context.get_variable(&namespace) {
+            Some(Value::Map(entries)) => entries.clone(),
+            _ => Default::default(),
+        };
+        entries.extend(scope.into_iter().flat_map(|scope| scope.variables));
+        self.context.set_variable(namespace, Value::Map(entries));
         Ok(())
     }
 
-    /// Routes a command to its appropriate handler based on the command name.
-    /// Special handling for FN and ENDFN commands, with all others treated as regular commands.
-    fn handle_command(&mut self, command: Box<dyn Command>, line: &str) -> Result<(), String> {
-        match command.name() {
-            "FN" => self.handle_fn_start(),
-            "ENDFN" => self.handle_fn_end(),
-            _ => self.handle_regular_command(command, line),
-        }
+    /// Sets the DEFs loaded from an imported file, which the parser has checked
+    /// are the only statements at its top level.
+    fn run_imported_defs(&mut self) -> Result<(), CerealError> {
+        let defs = std::mem::take(&mut self.main);
+        Self::run_chunk(&defs, &mut self.context).map(|_| ())
     }
 
-    /// Handles the start of a function definition (FN command).
-    /// Creates a new function context with the given name and empty body.
-    fn handle_fn_start(&mut self) -> Result<(), String> {
-        let name = self.parser.get_last_args().unwrap_or_default()[1].clone();
-        self.current_fn = Some((name, Vec::new()));
-        Ok(())
+    /// Takes a snapshot of the loaded program, e.g. to save it with `cereal build`.
+    /// Functions are sorted by name so building the same script always gives the same bytes.
+    pub fn compiled_program(&self) -> CompiledProgram {
+        let mut functions: Vec<(String, Function)> = self.functions
+            .iter()
+            .map(|(name, function)| (name.clone(), function.as_ref().clone()))
+            .collect();
+        functions.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // The environment is read again when the program runs
+        let mut globals: Vec<(String, Value)> = self.context.variables
+            .iter()
+            .filter(|(name, _)| name.as_str() != ScriptVariables::ENV)
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+
+        CompiledProgram { main: self.main.clone(), functions, globals }
     }
 
-    /// Handles the end of a function definition (ENDFN command).
-    /// Stores the collected function body if there is a matching FN,
-    /// otherwise returns an error.
-    fn handle_fn_end(&mut self) -> Result<(), String> {
-        if let Some((name, body)) = self.current_fn.take() {
-            self.define_function(&name, body)
-        } else {
-            Err("ENDFN without matching FN".to_string())
+    /// Loads a program compiled earlier, as if its script had been loaded with `load_source`
+    pub fn load_compiled(&mut self, program: CompiledProgram) {
+        for (name, function) in program.functions {
+            self.functions.insert(name, Rc::new(function));
+        }
+        for (name, value) in program.globals {
+            self.context.set_variable(name, value);
         }
+        self.main.append(program.main);
     }
 
-    /// Handles regular commands (non-FN/ENDFN).
-    /// If inside a function definition, adds the command to the function body.
-    /// Otherwise, adds it to the main command list for execution.
-    fn handle_regular_command(&mut self, command: Box<dyn Command>, line: &str) -> Result<(), String> {
-        if let Some((_, ref mut body)) = self.current_fn {
-            // If we're in a function definition, add to body
-            body.push(line.to_string());
-        } else {
-            // Otherwise add to normal commands
-            self.add_command(command);
+    /// Calls a previously defined function by name with the given argument values.
+    /// Binds each argument to its parameter in a new local scope, then runs the function's body.
+    /// Returns the value passed to RETURN, if any, or an error if the function is not found.
+    pub fn call_function(context: &mut ExecutionContext, name: &str, args: Vec<Value>) -> Result<Option<Value>, CerealError> {
+        // Functions imported into a namespace call each other without the prefix
+        let namespace = context.scopes.last().and_then(|scope| scope.namespace.clone());
+        let vm = context.get_vm();
+        let function = namespace
+            .and_then(|namespace| vm.functions.get(&format!("{}.{}", namespace, name)))
+            .or_else(|| vm.functions.get(name))
+            .cloned()
+            .ok_or_else(|| CerealError::runtime(format!("Function '{}' not found", name)))?;
+
+        if args.len() != function.params.len() {
+            return Err(CerealError::runtime(format!(
+                "Function '{}' expects {} argument(s), got {}",
+                name, function.params.len(), args.len()
+            )));
         }
-        Ok(())
+
+        // Each call gets its own scope, starting with the DEFs of its namespace and the parameters
+        context.push_scope();
+        context.push_defer_frame();
+        if let Some(namespace) = &function.namespace {
+            let mut parts = namespace.split('.');
+            let definitions = parts
+                .next()
+                .and_then(|first| context.variables.get(first))
+                .and_then(|value| parts.try_fold(value, |value, part| value.get(part)));
+            if let Some(Value::Map(entries)) = definitions.cloned() {
+                for (name, value) in entries {
+                    context.set_variable(name, value);
+                }
+            }
+            if let Some(scope) = context.scopes.last_mut() {
+                scope.namespace = Some(namespace.clone());
+            }
+        }
+        for (param, value) in function.params.iter().zip(args) {
+            context.set_variable(param.clone(), value);
+        }
+
+        let (value, result) = match Self::run_chunk(&function.code, context) {
+            Ok(value) => (value, Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let result = Self::run_deferred(context, result);
+        context.pop_scope();
+
+        result?;
+        Ok(value)
     }
 
-    /// Defines a new function with the given name and body.
-    /// Stores the function in the VM's function map for later execution.
-    pub fn define_function(&mut self, name: &str, body: Vec<String>) -> Result<(), String> {
-        self.functions.insert(name.to_string(), body);
-        Ok(())
+    /// Returns the current value of a variable, if it has been set.
+    pub fn get_variable(&self, name: &str) -> Option<&Value> {
+        self.context.variables.get(name)
+    }
+
+    pub fn execute_line(&mut self, line: &str) -> Result<(), CerealError> {
+        Self::run_line(&mut self.parser, line, &mut self.context)
     }
 
-    /// Calls a previously defined function by name.
-    /// Executes each line in the function's body, maintaining the call stack for proper returns.
-    /// Returns an error if the function is not found.
-    pub fn call_function(&mut self, name: &str) -> Result<(), String> {
-        let body = self.functions.get(name).ok_or_else(|| {
-            format!("Function '{}' not found", name)
-        })?.clone();
-
-        // Save current position
-        self.call_stack.push(self.current_line);
-        
-        // Execute function body
-        for line in body {
-            if let Some(command) = self.parser.parse_line(&line)? {
-                // Check if we should skip this command
-                if !self.context.should_skip(command.name()) || command.is_control_flow() {
-                    command.execute(&mut self.context)?;
+    /// Makes the command-line arguments after the script path available to the script.
+    /// Positional arguments are stored in `$args`, `$argc` and `$arg1`, `$arg2`, ...,
+    /// while flags become variables: `--out=dist` sets `$out` and `--dry-run` sets `$dry_run` to true.
+    /// Anything after `--` is positional. Flags without a name, or named after `$env` or one of the
+    /// argument variables, are an error.
+    pub fn set_script_args(&mut self, args: &[String]) -> Result<(), CerealError> {
+        let mut positional = Vec::new();
+        let mut flags = Vec::new();
+        let mut flags_done = false;
+
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some("") if !flags_done => flags_done = true,
+                Some(flag) if !flags_done => {
+                    let (name, value) = match flag.split_once('=') {
+                        Some((name, value)) => (name, Value::from(value)),
+                        None => (flag, Value::Bool(true)),
+                    };
+                    let name = name.replace('-', "_");
+                    if name.is_empty() {
+                        return Err(CerealError::runtime(format!("Flag '{}' has no name", arg)));
+                    }
+                    if ScriptVariables::is_reserved(&name) {
+                        return Err(CerealError::runtime(format!(
+                            "Flag '{}' can't be used, as ${} is set by the VM",
+                            arg, name
+                        )));
+                    }
+                    flags.push((name, value));
                 }
+                _ => positional.push(Value::from(arg.as_str())),
             }
         }
 
-        // Restore position
-        if let Some(return_line) = self.call_stack.pop() {
-            self.current_line = return_line;
+        for (name, value) in flags {
+            self.context.set_variable(name, value);
         }
 
+        for (index, arg) in positional.iter().enumerate() {
+            self.context.set_variable(format!("{}{}", ScriptVariables::ARG_PREFIX, index + 1), arg.clone());
+        }
+        self.context.set_variable(ScriptVariables::ARGC.to_string(), positional.len() as i64);
+        self.context.set_variable(ScriptVariables::ARGS.to_string(), Value::List(positional));
         Ok(())
     }
 
-    pub fn execute_line(&mut self, line: &str) -> Result<(), String> {
-        if let Some(command) = self.parser.parse_line(line)? {
-            command.execute(&mut self.context)
-        } else {
-            Ok(())
-        }
+    /// Chooses whether variables that aren't defined expand to an empty string instead of being an error
+    pub fn set_allow_undefined(&mut self, allow: bool) {
+        self.context.allow_undefined = allow;
+    }
+
+    /// Adds a filter that scripts can use when interpolating, e.g. `${name | myfilter}`
+    pub fn register_filter(&mut self, name: &str, filter: FilterFn) {
+        self.context.filters.register(name, filter);
     }
 
     pub fn set_register(&mut self, name: &str, value: String) {
@@ -231,4 +547,10 @@ impl VM {
     pub fn clear_all_registers(&mut self) {
         self.registers.clear();
     }
-}
\ No newline at end of file
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,6 @@
 use crate::vm::VM;
+use crate::error::CerealError;
+use crate::value::Value;
 
 #[test]
 fn test_basic_function_definition() {
@@ -57,15 +59,52 @@ fn test_function_body_capture() {
     
     assert!(vm.load_string(script).is_ok());
     
-    if let Some(body) = vm.functions.get("test_func") {
-        assert_eq!(body.len(), 2);
-        assert!(body[0].contains("Line 1"));
-        assert!(body[1].contains("Line 2"));
+    if let Some(function) = vm.functions.get("test_func") {
+        assert_eq!(function.code.ops.len(), 4);
+        assert!(function.code.location(0).unwrap().text.contains("Line 1"));
+        assert!(function.code.location(2).unwrap().text.contains("Line 2"));
     } else {
         panic!("Function not found");
     }
 }
 
+#[test]
+fn test_function_bodies_are_checked_when_loading() {
+    // Errors inside a function are reported even if it is never called
+    let invalid = [
+        "FN unused DO\n    FOO bar\nENDFN",
+        "FN unused DO\n    MOV\nENDFN",
+        "FN unused DO\n    PRINT \"unterminated\nENDFN",
+        "FN unused DO\n    IF $a IS\n    ENDIF\nENDFN",
+    ];
+    for script in invalid {
+        let mut vm = VM::new();
+        match vm.load_source("script.cereal", script) {
+            Err(error) => assert_eq!(error.location().map(|location| location.line), Some(2), "{}", script),
+            Ok(_) => panic!("{}: expected an error", script),
+        }
+    }
+
+    // Calls share the body compiled when loading
+    let mut vm = VM::new();
+    let script = r#"
+        FN count DO
+            GLOBAL n
+            ADD n 1
+        ENDFN
+        MOV n 0
+        WHILE $n LT 100
+            CALL count
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    let code = vm.functions["count"].code.ops.as_ptr();
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("n"), Some(&Value::from(100)));
+    assert_eq!(vm.functions["count"].code.ops.as_ptr(), code);
+}
+
 #[test]
 fn test_register_operations() {
     let mut vm = VM::new();
@@ -111,4 +150,1400 @@ fn test_execute_line() {
     
     // Test empty line
     assert!(vm.execute_line("").is_ok());
-} 
\ No newline at end of file
+}
+
+#[test]
+fn test_while_loop() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV state "run"
+        MOV count ""
+        WHILE $state IS "run"
+            MOV count "$count."
+            IF $count IS "..."
+                MOV state "done"
+            ENDIF
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("count"), Some(&Value::from("...")));
+}
+
+#[test]
+fn test_nested_while_loops() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV outer ""
+        MOV total ""
+        WHILE $outer NOT ".."
+            MOV outer "$outer."
+            MOV inner ""
+            WHILE $inner NOT "..."
+                MOV inner "$inner."
+                MOV total "$total+"
+            ENDWHILE
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("total"), Some(&Value::from("++++++")));
+}
+
+#[test]
+fn test_while_loop_skipped_when_false() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV ran "no"
+        WHILE "a" IS "b"
+            WHILE "a" IS "a"
+                MOV ran "yes"
+            ENDWHILE
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("ran"), Some(&Value::from("no")));
+}
+
+#[test]
+fn test_while_loop_in_function() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN fill DO
+            GLOBAL bar
+            WHILE $bar NOTCONTAINS "***"
+                MOV bar "$bar*"
+            ENDWHILE
+        ENDFN
+        MOV bar ""
+        CALL fill
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("bar"), Some(&Value::from("***")));
+}
+
+#[test]
+fn test_unbalanced_while() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("WHILE $a IS \"b\"").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ENDWHILE").is_err());
+
+    let mut vm = VM::new();
+    let script = r#"
+        FN broken DO
+            WHILE $a IS "b"
+        ENDFN
+    "#;
+    assert!(vm.load_string(script).is_err());
+}
+
+#[test]
+fn test_if_else() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV status "500"
+        IF $status IS "200"
+            MOV result "ok"
+        ELSE
+            MOV result "failed"
+        ENDIF
+        IF $status IS "500"
+            MOV other "matched"
+        ELSE
+            MOV other "else"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("failed")));
+    assert_eq!(vm.get_variable("other"), Some(&Value::from("matched")));
+}
+
+#[test]
+fn test_elseif_runs_exactly_one_branch() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV status "404"
+        MOV branches ""
+        IF $status IS "200"
+            MOV branches "$branches ok"
+        ELSEIF $status IS "404"
+            MOV branches "$branches missing"
+        ELSEIF $status CONTAINS "4"
+            MOV branches "$branches client"
+        ELSE
+            MOV branches "$branches other"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("branches"), Some(&Value::from(" missing")));
+}
+
+#[test]
+fn test_nested_if_inside_skipped_if() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV outer "no"
+        MOV inner "yes"
+        MOV after_inner "unchanged"
+        IF $outer IS "yes"
+            IF $inner IS "yes"
+                MOV inner "ran"
+            ENDIF
+            MOV after_inner "ran"
+        ELSE
+            MOV outer "else"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("inner"), Some(&Value::from("yes")));
+    assert_eq!(vm.get_variable("after_inner"), Some(&Value::from("unchanged")));
+    assert_eq!(vm.get_variable("outer"), Some(&Value::from("else")));
+}
+
+#[test]
+fn test_nested_if_in_function() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN classify DO
+            GLOBAL kind
+            IF $code CONTAINS "4"
+                IF $code IS "404"
+                    MOV kind "missing"
+                ELSE
+                    MOV kind "client"
+                ENDIF
+            ELSE
+                MOV kind "other"
+            ENDIF
+        ENDFN
+        MOV code "403"
+        CALL classify
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("kind"), Some(&Value::from("client")));
+}
+
+#[test]
+fn test_unbalanced_if() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("IF $a IS \"b\"").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ENDIF").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ELSE").is_err());
+
+    let mut vm = VM::new();
+    let script = r#"
+        IF $a IS "b"
+            WHILE $a IS "b"
+        ENDIF
+        ENDWHILE
+    "#;
+    assert!(vm.load_string(script).is_err());
+}
+
+#[test]
+fn test_function_parameters_and_return() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN greet name greeting DO
+            RETURN "$greeting, $name!"
+        ENDFN
+        DEF who "Bob"
+        CALL greet $who "Hi" -> result
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("Hi, Bob!")));
+}
+
+#[test]
+fn test_return_stops_function() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN check value DO
+            GLOBAL reached
+            IF $value IS "ok"
+                RETURN "early"
+            ENDIF
+            MOV reached "yes"
+            RETURN "late"
+        ENDFN
+        MOV reached "no"
+        CALL check "ok" -> first
+        CALL check "bad" -> second
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("first"), Some(&Value::from("early")));
+    assert_eq!(vm.get_variable("second"), Some(&Value::from("late")));
+    assert_eq!(vm.get_variable("reached"), Some(&Value::from("yes")));
+}
+
+#[test]
+fn test_call_with_wrong_argument_count() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN greet name DO
+            PRINT "Hello $name"
+        ENDFN
+        CALL greet
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_err());
+}
+
+#[test]
+fn test_function_variables_are_local() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN shadow name DO
+            MOV status "shadowed"
+            MOV r0 "register"
+            RETURN "$status $name $site"
+        ENDFN
+        DEF site "example.com"
+        MOV status "caller"
+        CALL shadow "inner" -> result
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("shadowed inner example.com")));
+    assert_eq!(vm.get_variable("status"), Some(&Value::from("caller")));
+    assert_eq!(vm.get_variable("name"), None);
+    assert_eq!(vm.get_variable("r0"), None);
+}
+
+#[test]
+fn test_global_writes_to_outer_scope() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN outer DO
+            MOV secret "outer"
+            GLOBAL counter
+            MOV counter "$counter+"
+            CALL inner
+        ENDFN
+        FN inner DO
+            GLOBAL seen
+            MOV seen "[$secret]"
+        ENDFN
+        MOV counter "+"
+        CALL outer
+    "#;
+
+    // secret only exists in outer's scope, so it can't be seen from inner
+    vm.set_allow_undefined(true);
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("counter"), Some(&Value::from("++")));
+    assert_eq!(vm.get_variable("seen"), Some(&Value::from("[]")));
+}
+
+#[test]
+fn test_arithmetic_commands() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV count 10
+        ADD count 5
+        SUB count 3
+        MUL count 2
+        MOV half 0
+        DIV half $count 5
+        MOD rest $count 5
+        DIV ratio 7 2.0
+        ADD sum 0.5 -2
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("count"), Some(&Value::from(24)));
+    assert_eq!(vm.get_variable("half"), Some(&Value::from(4)));
+    assert_eq!(vm.get_variable("rest"), Some(&Value::from(4)));
+    assert_eq!(vm.get_variable("ratio"), Some(&Value::from(3.5)));
+    assert_eq!(vm.get_variable("sum"), Some(&Value::from(-1.5)));
+}
+
+#[test]
+fn test_numeric_comparisons_in_loop() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV retries 0
+        MOV log ""
+        WHILE $retries LT 3
+            ADD retries 1
+            IF $retries GTE 2
+                MOV log "$log!"
+            ELSEIF $retries GT 0
+                MOV log "$log."
+            ENDIF
+        ENDWHILE
+        IF 10 LTE 9.5
+            MOV log "wrong"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("retries"), Some(&Value::from(3)));
+    assert_eq!(vm.get_variable("log"), Some(&Value::from(".!!")));
+}
+
+#[test]
+fn test_text_comparisons_use_the_text_of_numbers() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV count 7
+        MOV half 3.5
+        ADD half 3.5
+        IF $count IS "7" AND 007 NOT 7 AND 007 GTE $count
+            MOV same "yes"
+        ENDIF
+        IF $half IS 7 AND $half IS $count
+            MOV sum "yes"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("same"), Some(&Value::from("yes")));
+    assert_eq!(vm.get_variable("sum"), Some(&Value::from("yes")));
+}
+
+#[test]
+fn test_non_numeric_values_are_errors() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("MOV name \"bob\"\nADD name 1").is_ok());
+    assert!(vm.execute().unwrap_err().to_string().starts_with("<input>:2:1: ADD: 'bob' is not a number\n"));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ADD missing 1").is_ok());
+    assert!(vm.execute().is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("DIV result 1 0").is_ok());
+    assert!(vm.execute().unwrap_err().to_string().starts_with("<input>:1:1: DIV: Division by zero\n"));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("MOV x 1\nMUL x 1e308 10").is_ok());
+    assert!(vm.execute().unwrap_err().to_string().starts_with("<input>:2:1: MUL: Float overflow\n"));
+    assert_eq!(vm.get_variable("x"), Some(&Value::from(1)));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("IF \"abc\" GT 1\nENDIF").is_ok());
+    assert!(vm.execute().unwrap_err().to_string().starts_with("<input>:1:1: GT: 'abc' is not a number\n"));
+}
+
+#[test]
+fn test_compound_conditions() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV status "200"
+        MOV body "all ok"
+        MOV force "0"
+        IF ($status IS "200" AND $body CONTAINS "ok") OR $force IS "1"
+            MOV first "yes"
+        ENDIF
+        IF $status IS "200" AND NOT ($body CONTAINS "ok" OR $force IS "1")
+            MOV second "yes"
+        ELSE
+            MOV second "no"
+        ENDIF
+        IF $force IS "1" OR $status NOT "200" OR $body IS "AND"
+            MOV third "yes"
+        ELSE
+            MOV third "no"
+        ENDIF
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("first"), Some(&Value::from("yes")));
+    assert_eq!(vm.get_variable("second"), Some(&Value::from("no")));
+    assert_eq!(vm.get_variable("third"), Some(&Value::from("no")));
+}
+
+#[test]
+fn test_conditions_short_circuit() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV count "none"
+        IF $count IS "none" OR $count GT 1
+            MOV result "ok"
+        ENDIF
+        WHILE $count NOT "none" AND $count LT 1
+            MOV result "unreachable"
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("ok")));
+}
+
+#[test]
+fn test_load_error_location() {
+    let mut vm = VM::new();
+    let script = "PRINT \"ok\"\n    FOO bar";
+
+    assert_eq!(
+        vm.load_source("script.cereal", script).map_err(|e| e.to_string()),
+        Err("script.cereal:2:5: Expected command or macro, got Identifier\n 2 |     FOO bar\n   |     ^".to_string())
+    );
+}
+
+#[test]
+fn test_lex_error_location() {
+    let mut vm = VM::new();
+    let script = "\n\nPRINT \"Unterminated";
+
+    assert_eq!(
+        vm.load_string(script).map_err(|e| e.to_string()),
+        Err("<input>:3:7: Unterminated string literal\n 3 | PRINT \"Unterminated\n   |       ^".to_string())
+    );
+}
+
+#[test]
+fn test_unclosed_block_location() {
+    let mut vm = VM::new();
+    let script = "MOV a 1\n  IF $a IS 1\n";
+
+    let error = vm.load_source("script.cereal", script).unwrap_err().to_string();
+    assert!(error.starts_with("script.cereal:2:3: Unclosed IF block"), "{}", error);
+}
+
+#[test]
+fn test_runtime_error_location_inside_function() {
+    let mut vm = VM::new();
+    let script = r#"FN broken DO
+    MOV name "bob"
+    ADD name 1
+ENDFN
+CALL broken"#;
+
+    assert!(vm.load_source("script.cereal", script).is_ok());
+    assert_eq!(
+        vm.execute().map_err(|e| e.to_string()),
+        Err("script.cereal:3:5: ADD: 'bob' is not a number\n 3 |     ADD name 1\n   |     ^".to_string())
+    );
+}
+
+#[test]
+fn test_error_kinds() {
+    let mut vm = VM::new();
+    let error = vm.load_string("PRINT \"Unterminated").unwrap_err();
+    assert!(matches!(error, CerealError::Lex { .. }));
+    assert_eq!(error.location().map(|location| location.column), Some(7));
+
+    let mut vm = VM::new();
+    assert!(matches!(vm.load_string("ENDIF").unwrap_err(), CerealError::Parse { .. }));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("!nosuchlib \"arg\"").is_ok());
+    let error = vm.execute().unwrap_err();
+    assert!(matches!(error, CerealError::UnknownLibrary { ref name, .. } if name == "nosuchlib"));
+    assert_eq!(error.message(), "Library 'nosuchlib' not found");
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("CALL missing").is_ok());
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error.kind(), "Runtime");
+    assert_eq!(error.location().map(|location| location.line), Some(1));
+}
+
+#[test]
+fn test_abort_stops_script_with_error() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV term "cereal"
+        ABORT "Website does not contain $term"
+        MOV after "ran"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    let error = vm.execute().unwrap_err();
+    assert!(matches!(
+        error,
+        CerealError::Aborted { ref message, code: 1, .. } if message == "Website does not contain cereal"
+    ));
+    assert_eq!(error.location().map(|location| location.line), Some(3));
+    assert_eq!(vm.get_variable("after"), None);
+
+    // The VM is still usable afterwards, e.g. by the REPL
+    vm.clear_commands();
+    assert!(vm.load_string("MOV after \"ran $term\"").is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("after"), Some(&Value::from("ran cereal")));
+}
+
+#[test]
+fn test_abort_with_exit_code() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN check DO
+            ABORT "Check failed" 3
+        ENDFN
+        CALL check
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(matches!(vm.execute(), Err(CerealError::Aborted { code: 3, .. })));
+
+    // A number on its own is the message, not the exit code
+    let mut vm = VM::new();
+    assert!(vm.load_string("ABORT 42").is_ok());
+    assert!(matches!(
+        vm.execute(),
+        Err(CerealError::Aborted { ref message, code: 1, .. }) if message == "42"
+    ));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ABORT \"Bad code\" 1.5").is_err());
+}
+
+#[test]
+fn test_try_catch() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV name "bob"
+        TRY
+            MOV before "ran"
+            IF $name IS "bob"
+                ADD name 1
+            ENDIF
+            MOV after "ran"
+        CATCH err
+            MOV handled "yes"
+        ENDTRY
+        TRY
+            MOV ok "yes"
+        CATCH unused
+            MOV unused_ran "yes"
+        ENDTRY
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("before"), Some(&Value::from("ran")));
+    assert_eq!(vm.get_variable("after"), None);
+    assert_eq!(vm.get_variable("handled"), Some(&Value::from("yes")));
+    assert_eq!(vm.get_variable("err"), Some(&Value::from("ADD: 'bob' is not a number")));
+    assert_eq!(vm.get_variable("err_kind"), Some(&Value::from("Runtime")));
+    assert_eq!(vm.get_variable("ok"), Some(&Value::from("yes")));
+    assert_eq!(vm.get_variable("unused_ran"), None);
+}
+
+#[test]
+fn test_try_catches_errors_from_functions() {
+    let script = r#"
+        FN fetch DO
+            !nosuchlib "arg"
+        ENDFN
+        TRY
+            CALL fetch
+        CATCH err
+            MOV caught $err_kind
+        FINALLY
+            MOV cleanup "done"
+        ENDTRY
+    "#;
+
+    let mut vm = VM::new();
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("caught"), Some(&Value::from("UnknownLibrary")));
+    assert_eq!(vm.get_variable("cleanup"), Some(&Value::from("done")));
+}
+
+#[test]
+fn test_finally_runs_and_rethrows() {
+    // Errors raised inside CATCH still run FINALLY before carrying on to the caller
+    let mut vm = VM::new();
+    let script = r#"
+        TRY
+            DIV result 1 0
+        CATCH err
+            ADD missing 1
+        FINALLY
+            MOV cleanup "done"
+        ENDTRY
+        MOV after "ran"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error.message(), "ADD: Variable 'missing' is not defined");
+    assert_eq!(vm.get_variable("err"), Some(&Value::from("DIV: Division by zero")));
+    assert_eq!(vm.get_variable("cleanup"), Some(&Value::from("done")));
+    assert_eq!(vm.get_variable("after"), None);
+}
+
+#[test]
+fn test_abort_runs_finally_but_is_not_caught() {
+    let mut vm = VM::new();
+    let script = r#"
+        TRY
+            TRY
+                ABORT "Stop" 2
+            CATCH err
+                MOV inner_caught "yes"
+            ENDTRY
+        CATCH err
+            MOV outer_caught "yes"
+        FINALLY
+            MOV cleanup "done"
+        ENDTRY
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(matches!(vm.execute(), Err(CerealError::Aborted { code: 2, .. })));
+    assert_eq!(vm.get_variable("inner_caught"), None);
+    assert_eq!(vm.get_variable("outer_caught"), None);
+    assert_eq!(vm.get_variable("cleanup"), Some(&Value::from("done")));
+}
+
+#[test]
+fn test_finally_runs_when_leaving_early() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV log "start"
+        FN work DO
+            GLOBAL log
+            TRY
+                RETURN "early"
+            FINALLY
+                MOV log "$log,finally"
+            ENDTRY
+            MOV log "$log,unreachable"
+        ENDFN
+        CALL work -> result
+        MOV numbers [1, 2, 3]
+        FOR n IN $numbers DO
+            TRY
+                IF $n IS 2
+                    BREAK
+                ENDIF
+            FINALLY
+                MOV log "$log,$n"
+            ENDTRY
+        ENDFOR
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("early")));
+    assert_eq!(vm.get_variable("log"), Some(&Value::from("start,finally,1,2")));
+}
+
+#[test]
+fn test_errors_caught_inside_loops() {
+    // Errors inside a loop don't upset the items of the loops around it
+    let mut vm = VM::new();
+    let script = r#"
+        MOV log ""
+        MOV outer [1, 2]
+        MOV inner ["a", "b", "c"]
+        FOR i IN $outer DO
+            FOR item IN $inner DO
+                TRY
+                    IF $item IS "b"
+                        CONTINUE
+                    ENDIF
+                    MOV value $missing
+                CATCH err
+                    MOV log "$log$i$item,"
+                FINALLY
+                    MOV log "$log."
+                ENDTRY
+            ENDFOR
+        ENDFOR
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("log"), Some(&Value::from("1a,..1c,.2a,..2c,.")));
+}
+
+#[test]
+fn test_functions_are_compiled_to_bytecode() {
+    use crate::bytecode::Op;
+    use crate::expression::Comparison;
+    use crate::value::Operand;
+
+    let mut vm = VM::new();
+    let script = r#"
+        FN greet name DO
+            MOV r0 "hi $name"
+            RETURN $r0
+        ENDFN
+        FN twice DO
+            CALL greet "a" -> first
+            WHILE $first IS "b"
+            ENDWHILE
+        ENDFN
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert_eq!(vm.functions["greet"].code.ops, [
+        Op::Move("r0".to_string(), Operand::Text("hi $name".to_string())),
+        Op::Load(Operand::Text("$r0".to_string())),
+        Op::Ret,
+    ]);
+
+    let ops = &vm.functions["twice"].code.ops;
+    assert_eq!(ops[..3], [
+        Op::Load(Operand::Text("a".to_string())),
+        Op::Call("greet".to_string(), 1),
+        Op::Store("first".to_string()),
+    ]);
+    assert_eq!(ops[3..], [
+        Op::Load(Operand::Text("$first".to_string())),
+        Op::Load(Operand::Literal(Value::from("b"))),
+        Op::Compare(Comparison::Is),
+        Op::JumpIfFalse(8),
+        Op::Jump(3),
+    ]);
+}
+
+#[test]
+fn test_try_blocks_must_be_balanced() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("TRY\nMOV a 1\n").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("CATCH err\nENDTRY").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("TRY\nIF $a IS 1\nCATCH err\nENDIF\nENDTRY").is_err());
+}
+
+#[test]
+fn test_defer_runs_in_reverse_order_when_function_returns() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV log "start"
+        FN work DO
+            GLOBAL log
+            DEFER MOV log "$log,first"
+            DEFER MOV log "$log,second"
+            MOV log "$log,body"
+            RETURN "done"
+            MOV log "$log,unreachable"
+        ENDFN
+        DEFER MOV log "$log,script"
+        CALL work -> result
+        MOV log "$log,after"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from("done")));
+    assert_eq!(vm.get_variable("log"), Some(&Value::from("start,body,second,first,after,script")));
+}
+
+#[test]
+fn test_defer_runs_on_error_and_abort() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV log "start"
+        FN broken DO
+            GLOBAL log
+            DEFER MOV log "$log,cleanup"
+            DIV result 1 0
+        ENDFN
+        CALL broken
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert_eq!(vm.execute().unwrap_err().message(), "DIV: Division by zero");
+    assert_eq!(vm.get_variable("log"), Some(&Value::from("start,cleanup")));
+
+    let mut vm = VM::new();
+    let script = r#"
+        DEFER MOV cleanup "done"
+        ABORT "Stop" 5
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(matches!(vm.execute(), Err(CerealError::Aborted { code: 5, .. })));
+    assert_eq!(vm.get_variable("cleanup"), Some(&Value::from("done")));
+}
+
+#[test]
+fn test_deferred_errors() {
+    // Every deferred command runs, and the first error is reported at the DEFER line
+    let mut vm = VM::new();
+    let script = "DEFER MOV second \"ran\"\nDEFER ADD missing 1\n";
+
+    assert!(vm.load_string(script).is_ok());
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error.location().map(|location| (location.line, location.column)), Some((2, 7)));
+    assert_eq!(vm.get_variable("second"), Some(&Value::from("ran")));
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("DEFER IF $a IS 1").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("DEFER \"text\"").is_err());
+}
+
+#[test]
+fn test_for_lines_words_and_split() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV output "alpha\nbeta\n\ngamma\n"
+        MOV lines ""
+        FOR line IN $output LINES DO
+            MOV lines "$lines[$line]"
+        ENDFOR
+        MOV words ""
+        FOR word IN "  one two   three " WORDS DO
+            MOV words "$words<$word>"
+        ENDFOR
+        MOV total 0
+        FOR n IN "1,2,,3" SPLIT "," DO
+            IF $n NOT ""
+                ADD total $n
+            ENDIF
+        ENDFOR
+        FOR never IN "" LINES DO
+            MOV ran "yes"
+        ENDFOR
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("lines"), Some(&Value::from("[alpha][beta][][gamma]")));
+    assert_eq!(vm.get_variable("words"), Some(&Value::from("<one><two><three>")));
+    assert_eq!(vm.get_variable("total"), Some(&Value::from(6)));
+    assert_eq!(vm.get_variable("ran"), None);
+}
+
+#[test]
+fn test_break_and_continue() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV seen ""
+        FOR n IN "1 2 3 4 5" WORDS DO
+            IF $n IS 2
+                CONTINUE
+            ENDIF
+            IF $n IS 4
+                BREAK
+            ENDIF
+            MOV seen "$seen$n"
+        ENDFOR
+        MOV count 0
+        MOV odd 0
+        WHILE $count LT 10
+            ADD count 1
+            IF $count GT 6
+                BREAK
+            ENDIF
+            MOD rest $count 2
+            IF $rest IS 0
+                CONTINUE
+            ENDIF
+            ADD odd 1
+        ENDWHILE
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("seen"), Some(&Value::from("13")));
+    assert_eq!(vm.get_variable("count"), Some(&Value::from(7)));
+    assert_eq!(vm.get_variable("odd"), Some(&Value::from(3)));
+}
+
+#[test]
+fn test_nested_for_in_function() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN grid rows cols DO
+            MOV cells ""
+            FOR row IN $rows SPLIT "," DO
+                FOR col IN $cols WORDS DO
+                    IF $col IS "skip"
+                        CONTINUE
+                    ENDIF
+                    MOV cells "$cells $row$col"
+                ENDFOR
+                IF $row IS "b"
+                    BREAK
+                ENDIF
+            ENDFOR
+            RETURN $cells
+        ENDFN
+        CALL grid "a,b,c" "1 skip 2" -> result
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("result"), Some(&Value::from(" a1 a2 b1 b2")));
+}
+
+#[test]
+fn test_for_errors() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("BREAK").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("FOR x IN \"a b\" WORDS DO\nMOV y 1\n").is_err());
+
+    // Without LINES, WORDS or SPLIT the value has to be a list or map
+    let mut vm = VM::new();
+    assert!(vm.load_string("FOR x IN \"a b\" DO\nENDFOR").is_ok());
+    assert!(vm.execute().is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("FOR x IN \"a,b\" SPLIT DO\nENDFOR").is_err());
+}
+
+#[test]
+fn test_list_and_map_literals() {
+    let mut vm = VM::new();
+    let script = r#"
+        DEF hosts ["a.example.com", "b.example.com"]
+        DEF cfg {"port": 8080, "debug": false, tags: ["web", 2], "owner": null}
+        MOV empty []
+        MOV i 1
+        MOV first $hosts[0]
+        MOV second "$hosts[$i]"
+        MOV port $cfg.port
+        MOV url "http://$hosts[0]:$cfg.port/"
+        MOV tag $cfg.tags[1]
+        MOV copy $hosts
+        MOV text "hosts=$hosts owner=[$cfg.owner]"
+        MOV file "report"
+        MOV name "$file.txt $first[0] $cfg.missing"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    let hosts = Value::List(vec![Value::from("a.example.com"), Value::from("b.example.com")]);
+    assert_eq!(vm.get_variable("hosts"), Some(&hosts));
+    assert_eq!(vm.get_variable("copy"), Some(&hosts));
+    assert_eq!(vm.get_variable("empty"), Some(&Value::List(Vec::new())));
+    assert_eq!(vm.get_variable("first"), Some(&Value::from("a.example.com")));
+    assert_eq!(vm.get_variable("second"), Some(&Value::from("b.example.com")));
+    assert_eq!(vm.get_variable("port"), Some(&Value::from(8080)));
+    assert_eq!(vm.get_variable("url"), Some(&Value::from("http://a.example.com:8080/")));
+    assert_eq!(vm.get_variable("tag"), Some(&Value::from(2)));
+    assert_eq!(
+        vm.get_variable("text"),
+        Some(&Value::from("hosts=[\"a.example.com\", \"b.example.com\"] owner=[]"))
+    );
+    // Accessors that don't apply to the value are left as text
+    assert_eq!(
+        vm.get_variable("name"),
+        Some(&Value::from("report.txt a.example.com[0] {\"debug\": false, \"owner\": null, \"port\": 8080, \"tags\": [\"web\", 2]}.missing"))
+    );
+}
+
+#[test]
+fn test_lists_in_functions_and_loops() {
+    let mut vm = VM::new();
+    let script = r#"
+        FN last items DO
+            MOV out ""
+            FOR item IN $items DO
+                MOV out $item
+            ENDFOR
+            RETURN $out
+        ENDFN
+        FN pair DO
+            RETURN [1, "two"]
+        ENDFN
+        CALL last [3, 4, 5] -> from_literal
+        DEF nums [7, 8]
+        CALL last $nums -> from_var
+        CALL pair -> both
+        DEF cfg {"b": 1, "a": 2}
+        MOV keys ""
+        FOR key IN $cfg DO
+            MOV keys "$keys$key"
+        ENDFOR
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("from_literal"), Some(&Value::from(5)));
+    assert_eq!(vm.get_variable("from_var"), Some(&Value::from(8)));
+    assert_eq!(vm.get_variable("both"), Some(&Value::List(vec![Value::from(1), Value::from("two")])));
+    assert_eq!(vm.get_variable("keys"), Some(&Value::from("ab")));
+}
+
+#[test]
+fn test_invalid_literals() {
+    for literal in ["[1, 2", "[1 2]", "{\"a\" 1}", "{\"a\": }", "[1,]", "{1: 2}", "[1] 2"] {
+        let mut vm = VM::new();
+        assert!(vm.load_string(&format!("MOV x {}", literal)).is_err(), "{}", literal);
+    }
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("CALL f [1, 2").is_err());
+}
+
+#[test]
+fn test_interpolation() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV a "1"
+        MOV ab "2"
+        MOV price "$$5"
+        MOV dollar "$"
+        MOV raw "$$a"
+        DEF cfg {"port": 8080, "hosts": ["x", "y"]}
+        MOV braced "${a}b-$ab-${ab}c"
+        MOV nested "${cfg.hosts[1]}:${cfg.port}"
+        MOV text "$price and $raw"
+        MOV file "$a.txt"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("price"), Some(&Value::from("$5")));
+    assert_eq!(vm.get_variable("dollar"), Some(&Value::from("$")));
+    assert_eq!(vm.get_variable("raw"), Some(&Value::from("$a")));
+    assert_eq!(vm.get_variable("braced"), Some(&Value::from("1b-2-2c")));
+    assert_eq!(vm.get_variable("nested"), Some(&Value::from("y:8080")));
+    // Expanded values are not expanded again
+    assert_eq!(vm.get_variable("text"), Some(&Value::from("$5 and $a")));
+    assert_eq!(vm.get_variable("file"), Some(&Value::from("1.txt")));
+}
+
+#[test]
+fn test_undefined_variables() {
+    for line in ["PRINT \"$missing\"", "MOV x \"${missing}\"", "MOV x $missing", "MOV x \"${a.port}\"", "MOV x \"${a\""] {
+        let mut vm = VM::new();
+        assert!(vm.load_string(&format!("MOV a \"1\"\n{}", line)).is_ok(), "{}", line);
+        match vm.execute() {
+            Err(CerealError::Runtime { .. }) => {}
+            result => panic!("{}: expected a runtime error, got {:?}", line, result),
+        }
+    }
+
+    let mut vm = VM::new();
+    vm.set_allow_undefined(true);
+    assert!(vm.load_string("MOV x \"[$missing|${missing}]\"").is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("x"), Some(&Value::from("[|]")));
+}
+
+#[test]
+fn test_interpolation_defaults() {
+    let mut vm = VM::new();
+    let script = r#"
+        MOV port "8080"
+        MOV empty ""
+        DEF cfg {"host": "example.com"}
+        MOV a "${port:-80}|${missing:-80}|${empty:-none}|${missing:-$port}"
+        MOV b "${cfg.host:-localhost}|${cfg.name:-unnamed}"
+        MOV c "${port:+--port $port}|${missing:+--port $missing}|${empty:+set}"
+        MOV d "${port:?port is required}"
+        MOV e "${missing:-${port}0}"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("a"), Some(&Value::from("8080|80|none|8080")));
+    assert_eq!(vm.get_variable("b"), Some(&Value::from("example.com|unnamed")));
+    assert_eq!(vm.get_variable("c"), Some(&Value::from("--port 8080||")));
+    assert_eq!(vm.get_variable("d"), Some(&Value::from("8080")));
+    assert_eq!(vm.get_variable("e"), Some(&Value::from("80800")));
+}
+
+#[test]
+fn test_interpolation_required_variables() {
+    let mut vm = VM::new();
+    assert!(vm.load_string("MOV token \"\"\nPRINT \"${token:?Set a token for $0}\"").is_ok());
+    match vm.execute() {
+        Err(CerealError::Runtime { message, .. }) => assert_eq!(message, "Set a token for $0"),
+        result => panic!("expected a runtime error, got {:?}", result),
+    }
+
+    let mut vm = VM::new();
+    vm.set_allow_undefined(true);
+    assert!(vm.load_string("PRINT \"${missing:?}\"").is_ok());
+    match vm.execute() {
+        Err(CerealError::Runtime { message, .. }) => assert_eq!(message, "Variable 'missing' is not set"),
+        result => panic!("expected a runtime error, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_interpolation_filters() {
+    let mut vm = VM::new();
+    vm.register_filter("reverse", |value, _args| Ok(Value::String(value.to_string().chars().rev().collect())));
+    let script = r#"
+        MOV input "  Hello World  "
+        MOV sep "-"
+        DEF hosts ["a", "b", "c"]
+        MOV a "${input | trim | upper}|${input|lower}"
+        MOV b "${input | trim | replace ' ' $sep}"
+        MOV c "${input | trim | length} ${hosts | length}"
+        MOV d "${missing:-a b&c | urlencode}"
+        MOV e "${input | trim | reverse}"
+        MOV f "${input | replace 'o' '' | trim}"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("a"), Some(&Value::from("HELLO WORLD|  hello world  ")));
+    assert_eq!(vm.get_variable("b"), Some(&Value::from("Hello-World")));
+    assert_eq!(vm.get_variable("c"), Some(&Value::from("11 3")));
+    assert_eq!(vm.get_variable("d"), Some(&Value::from("a%20b%26c")));
+    assert_eq!(vm.get_variable("e"), Some(&Value::from("dlroW olleH")));
+    assert_eq!(vm.get_variable("f"), Some(&Value::from("Hell Wrld")));
+
+    for line in ["PRINT \"${input | shout}\"", "PRINT \"${input | upper 'x'}\"", "PRINT \"${input | replace 'a'}\"", "PRINT \"${input | }\""] {
+        let mut vm = VM::new();
+        assert!(vm.load_string(&format!("MOV input \"x\"\n{}", line)).is_ok(), "{}", line);
+        match vm.execute() {
+            Err(CerealError::Runtime { .. }) => {}
+            result => panic!("{}: expected a runtime error, got {:?}", line, result),
+        }
+    }
+}
+
+#[test]
+fn test_script_args() {
+    let mut vm = VM::new();
+    let args: Vec<String> = ["deploy", "--target=prod", "--dry-run", "eu", "--", "--not-a-flag"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    assert!(vm.set_script_args(&args).is_ok());
+    let script = r#"
+        MOV first "$arg1 $arg2 $arg3"
+        MOV count $argc
+        MOV last $args[2]
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("first"), Some(&Value::from("deploy eu --not-a-flag")));
+    assert_eq!(vm.get_variable("count"), Some(&Value::from(3)));
+    assert_eq!(vm.get_variable("last"), Some(&Value::from("--not-a-flag")));
+    assert_eq!(vm.get_variable("target"), Some(&Value::from("prod")));
+    assert_eq!(vm.get_variable("dry_run"), Some(&Value::Bool(true)));
+}
+
+#[test]
+fn test_script_flags_cannot_replace_builtin_variables() {
+    for flag in ["--args=x", "--argc=3", "--arg1=y", "--env=x", "--=x"] {
+        let mut vm = VM::new();
+        match vm.set_script_args(&[flag.to_string(), "one".to_string()]) {
+            Err(CerealError::Runtime { message, .. }) => assert!(message.starts_with(&format!("Flag '{}'", flag)), "{}", message),
+            result => panic!("{}: expected a runtime error, got {:?}", flag, result),
+        }
+        assert_eq!(vm.get_variable("argc"), None, "{}", flag);
+    }
+
+    // Names that only start like a built-in variable are fine
+    let mut vm = VM::new();
+    let args: Vec<String> = ["--argument=a", "--arg-count=2", "--environment=dev"].iter().map(|arg| arg.to_string()).collect();
+    assert!(vm.set_script_args(&args).is_ok());
+    assert_eq!(vm.get_variable("argument"), Some(&Value::from("a")));
+    assert_eq!(vm.get_variable("arg_count"), Some(&Value::from("2")));
+    assert_eq!(vm.get_variable("environment"), Some(&Value::from("dev")));
+    assert!(matches!(vm.get_variable("env"), Some(Value::Map(_))));
+}
+
+#[test]
+fn test_environment_variables() {
+    let mut vm = VM::new();
+    let script = r#"
+        ENV CEREAL_TEST_GREETING hello $name
+        MOV from_map "$env.CEREAL_TEST_GREETING"
+        EXEC "echo $$CEREAL_TEST_GREETING"
+        MOV from_child "${exec_stdout | trim}"
+        ENV CEREAL_TEST_GREETING
+        MOV removed "${env.CEREAL_TEST_GREETING:-gone}"
+    "#;
+
+    assert!(vm.load_string(&format!("MOV name \"world\"\n{}", script)).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("from_map"), Some(&Value::from("hello world")));
+    assert_eq!(vm.get_variable("from_child"), Some(&Value::from("hello world")));
+    assert_eq!(vm.get_variable("removed"), Some(&Value::from("gone")));
+    assert!(std::env::var("CEREAL_TEST_GREETING").is_err());
+
+    let mut vm = VM::new();
+    assert!(vm.load_string("ENV").is_err());
+    assert!(vm.load_string("ENV \"NAME\" x").is_err());
+    assert!(vm.load_string("ENV $name x").is_err());
+}
+
+/// Writes the given files into a new directory under the system temp directory
+fn write_files(dir: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(dir);
+    let _ = std::fs::remove_dir_all(&root);
+    for (name, content) in files {
+        let path = root.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    root
+}
+
+#[test]
+fn test_import() {
+    let root = write_files("cereal_test_import", &[
+        ("lib/common.cereal", "IMPORT \"helpers.cereal\"\nDEF greeting \"hello\"\nFN greet name DO\n    RETURN \"$greeting $name\"\nENDFN\nFN shout name DO\n    CALL greet $name -> text\n    RETURN \"${text | upper}\"\nENDFN\n"),
+        ("lib/helpers.cereal", "DEF sep \"-\"\nFN join a b DO\n    RETURN \"$a$sep$b\"\nENDFN\nMACRO label text DO\n    MOV labelled \"<$text>\"\nENDMACRO\n"),
+    ]);
+    let script = r#"
+        IMPORT "lib/common.cereal"
+        IMPORT "lib/common.cereal" AS c
+        CALL greet "bob" -> plain
+        CALL c.shout "amy" -> loud
+        CALL c.join "a" "b" -> joined
+        MOV value "$c.greeting$c.sep"
+        !c.label "x"
+    "#;
+
+    let mut vm = VM::new();
+    let path = root.join("main.cereal");
+    assert!(vm.load_source(path.to_str().unwrap(), script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("plain"), Some(&Value::from("hello bob")));
+    assert_eq!(vm.get_variable("loud"), Some(&Value::from("HELLO AMY")));
+    assert_eq!(vm.get_variable("joined"), Some(&Value::from("a-b")));
+    assert_eq!(vm.get_variable("value"), Some(&Value::from("hello-")));
+    assert_eq!(vm.get_variable("labelled"), Some(&Value::from("<x>")));
+    assert!(vm.functions.contains_key("join"));
+    assert!(vm.functions.contains_key("c.join"));
+}
+
+#[test]
+fn test_import_errors() {
+    let root = write_files("cereal_test_import_errors", &[
+        ("a.cereal", "IMPORT \"b.cereal\"\n"),
+        ("b.cereal", "IMPORT \"a.cereal\"\n"),
+        ("statements.cereal", "DEF x \"1\"\nPRINT \"hi\"\n"),
+        ("main.cereal", "IMPORT \"main.cereal\"\n"),
+    ]);
+
+    let load = |name: &str, script: &str| {
+        let mut vm = VM::new();
+        vm.load_source(root.join(name).to_str().unwrap(), script)
+    };
+
+    match load("main.cereal", "IMPORT \"a.cereal\"") {
+        Err(CerealError::Parse { message, location }) => {
+            assert!(message.starts_with("Import cycle:"), "{}", message);
+            assert!(location.unwrap().file.ends_with("b.cereal"));
+        }
+        result => panic!("expected an import cycle, got {:?}", result),
+    }
+    assert!(matches!(load("main.cereal", "IMPORT \"main.cereal\""), Err(CerealError::Parse { .. })));
+    match load("main.cereal", "IMPORT \"statements.cereal\"") {
+        Err(CerealError::Parse { location, .. }) => assert_eq!(location.unwrap().line, 2),
+        result => panic!("expected a parse error, got {:?}", result),
+    }
+    assert!(matches!(load("main.cereal", "IMPORT \"missing.cereal\""), Err(CerealError::Io { .. })));
+    assert!(load("main.cereal", "IF 1 IS 1\nIMPORT \"a.cereal\"\nENDIF").is_err());
+    assert!(load("main.cereal", "IMPORT a.cereal").is_err());
+    assert!(load("main.cereal", "IMPORT \"a.cereal\" AS").is_err());
+}
+
+#[test]
+fn test_user_macros() {
+    let mut vm = VM::new();
+    let script = r#"
+        MACRO greet who DO
+            MOV greeting "Hello $who"
+        ENDMACRO
+        MACRO count_to limit DO
+            MOV n 0
+            WHILE $n LT $limit
+                ADD n 1
+            ENDWHILE
+        ENDMACRO
+        MACRO writef file data DO
+            MOV written "$file=$data"
+        ENDMACRO
+        MACRO last items DO
+            MOV item $items[2]
+        ENDMACRO
+        MACRO both a b DO
+            !greet $a
+            MOV pair "$greeting & $b"
+        ENDMACRO
+        !greet "Bob \"B\""
+        MOV quoted $greeting
+        FN from_fn DO
+            !greet "fn"
+            RETURN $greeting
+        ENDFN
+        CALL from_fn -> in_fn
+        !count_to 3
+        !writef "out.txt" "data"
+        !last [1, 2, 3]
+        !both "x" "y"
+    "#;
+
+    assert!(vm.load_string(script).is_ok());
+    assert!(vm.execute().is_ok());
+    assert_eq!(vm.get_variable("quoted"), Some(&Value::from("Hello Bob \"B\"")));
+    assert_eq!(vm.get_variable("in_fn"), Some(&Value::from("Hello fn")));
+    assert_eq!(vm.get_variable("n"), Some(&Value::from(3)));
+    // User macros take precedence over libraries of the same name
+    assert_eq!(vm.get_variable("written"), Some(&Value::from("out.txt=data")));
+    assert_eq!(vm.get_variable("item"), Some(&Value::from(3)));
+    assert_eq!(vm.get_variable("pair"), Some(&Value::from("Hello x & y")));
+
+    // Other macros still go to the libraries
+    let mut vm = VM::new();
+    assert!(vm.load_string("MACRO greet DO\nENDMACRO\n!nosuchlib").is_ok());
+    assert!(matches!(vm.execute(), Err(CerealError::UnknownLibrary { .. })));
+}
+
+#[test]
+fn test_invalid_macros() {
+    let invalid = [
+        "MACRO greet who DO\nENDMACRO\n!greet",
+        "MACRO greet who DO\nENDMACRO\n!greet a b",
+        "MACRO loop DO\n!loop\nENDMACRO\n!loop",
+        "MACRO greet DO\nENDMACRO\nDEFER !greet",
+        "MACRO greet DO\nPRINT \"hi\"",
+        "ENDMACRO",
+        "MACRO greet DO\nFN f DO\nENDFN\nENDMACRO",
+        "MACRO greet DO\nIF 1 IS 1\nENDMACRO",
+        "FN f DO\nMACRO greet DO\nENDMACRO\nENDFN",
+        "MACRO greet",
+    ];
+    for script in invalid {
+        let mut vm = VM::new();
+        assert!(matches!(vm.load_string(script), Err(CerealError::Parse { .. })), "{}", script);
+    }
+
+    let mut vm = VM::new();
+    match vm.load_string("MACRO greet who DO\nENDMACRO\n\n!greet") {
+        Err(error) => assert_eq!(error.location().unwrap().line, 4),
+        Ok(_) => panic!("expected an error"),
+    }
+}